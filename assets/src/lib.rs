@@ -1,7 +1,7 @@
 /*use std::path::PathBuf;
 
 use util::{
-    meta_data::Deserialize,
+    meta_data::{Deserialize, Document, MetaError},
     prelude::*,
     sprite_sheet::{self, SpriteData}, store::{Table, Access}, create_access,
 };
@@ -13,13 +13,13 @@ macro_rules! impl_id_des {
         create_access!($($name)*);
         $(
             impl Deserialize<Assets> for $name {
-                fn deserialize_into(&mut self, state: &mut Assets, node: util::meta_data::Yaml) -> Result<(), String> {
+                fn deserialize_into(&mut self, state: &mut Assets, node: util::meta_data::Yaml) -> Result<(), MetaError> {
                     match node {
                         util::meta_data::Yaml::Scalar(name) => {
                             *self = state.$storage
                                 .get(name)
                                 .ok_or_else(||
-                                    format!(concat!(stringify!($name), " '{}' not found"), node)
+                                    MetaError::new(&node, format!(concat!(stringify!($name), " '{}' not found"), name))
                                 )?;
 
                         },
@@ -29,7 +29,7 @@ macro_rules! impl_id_des {
                                 state.$storage.push_anon(data);
                             }
                         )?
-                        _ => return Err(format!(concat!("expected ", stringify!($name), " name, got {}"), node)),
+                        _ => return Err(MetaError::expected(concat!(stringify!($name), " name"), &node)),
                     }
 
                     Ok(())
@@ -44,10 +44,10 @@ create_access!(
 );
 
 impl Deserialize<Assets> for Map {
-    fn deserialize_into(&mut self, state: &mut Assets, node: util::meta_data::Yaml) -> Result<(), String> {
+    fn deserialize_into(&mut self, state: &mut Assets, node: util::meta_data::Yaml) -> Result<(), MetaError> {
         match node {
 
-            _ => Err(format!("expected mapping, got {:?}", node)),
+            _ => Err(MetaError::expected("mapping", &node)),
         }
     }
 }
@@ -154,7 +154,8 @@ impl Assets {
 
                 $(
                     let mut $name = Table::new();
-                    assets.load_stats(paths, stringify!($name), &mut $name)?;
+                    assets.load_stats(paths, stringify!($name), &mut $name)
+                        .map_err(|err| err.to_string())?;
                     assets.$name = $name;
                 )*
             }
@@ -182,17 +183,22 @@ impl Assets {
         paths: &[&str],
         sub: &str,
         storage: &mut Table<K, T>,
-    ) -> Result<(), String> {
+    ) -> Result<(), MetaError> {
+        let mut error = None;
         walk_subdirectory(paths, ".yaml", &["stats", sub], |path| {
-            let content = std::fs::read_to_string(&path)
-                .map_err(|err| format!("Failed to open {} file '{}': {}", sub, &path, err))?;
-            let parsed = util::meta_data::parse(&content)
-                .map_err(|err| format!("Failed to parse {} YAML file '{}': {}", sub, &path, err))?;
-            storage
-                .deserialize_into(self, parsed)
-                .map_err(|err| format!("inside file '{}': {}", path, err))?;
-            Ok(())
+            // keep the structured error, walker only understands strings
+            Document::load(&path)
+                .and_then(|document| document.deserialize_into(self, storage))
+                .map_err(|err| {
+                    let message = err.to_string();
+                    error = Some(err);
+                    message
+                })
         })
+        .map_err(|message| error.take().unwrap_or_else(|| MetaError {
+            message,
+            ..MetaError::default()
+        }))
     }
 }
 
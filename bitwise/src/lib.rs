@@ -36,7 +36,7 @@ impl Encoder {
 
     pub fn data(&mut self) -> &[u8] {
         let len = ((self.data.len() - Self::LEN_SIZE) as u32).to_le_bytes();
-        self.data.copy_from_slice(&len);
+        self.data[..Self::LEN_SIZE].copy_from_slice(&len);
         &self.data
     }
 }
@@ -58,15 +58,23 @@ impl Decoder {
         self.buffer.len()
     }
 
+    /// Everything exposed, including what was decoded already.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    /// Position of the next value to decode.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
     pub fn expose(&mut self, size: usize) -> &mut [u8] {
         self.cursor = 0;
-        if self.buffer.capacity() < size {
-            self.buffer.reserve(size - self.buffer.capacity());
-        }
-        unsafe {
-            self.buffer.set_len(size);
-        }
-
+        self.buffer.resize(size, 0);
         &mut self.buffer
     }
 
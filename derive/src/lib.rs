@@ -1,4 +1,4 @@
-use proc_macro::{TokenStream, Literal};
+use proc_macro::TokenStream;
 use quote::ToTokens;
 use syn::{parenthesized, parse::Parse, spanned::Spanned, token, DeriveInput, Ident, LitInt};
 
@@ -43,47 +43,83 @@ pub fn meta_derive(input: TokenStream) -> TokenStream {
 
     let name = &input.ident;
 
-    let body = match &input.data {
-        syn::Data::Struct(data) => {
-            data.fields.iter().map(|field| {
-
-                let ident = &field.ident;
-                if field.attrs.iter().any(|attr|
-                    attr.path.segments.len() == 1 &&
-                    attr.path.segments.first().unwrap().ident.to_string().as_str() == "meta_required"
-                ) {
-                    quote::quote! {
-                        let field = util::meta_data::extract_field(&mut map, stringify!(#ident))
-                            .ok_or_else(|| format!("missing required field: {}", stringify!(#ident)))?;
-                        self.deserialize_into(state, field)
-                            .map_err(|err| format!("inside {}: {}", stringify!(#ident), err))?;
-                    }
-                } else {
-                    quote::quote! {
-                        if let Some(field) = util::meta_data::extract_field(&mut map, stringify!(#ident)) {
-                            self.deserialize_into(state, field)
-                                .map_err(|err| format!("inside {}: {}", stringify!(#ident), err))?;
-                        }
-                    }
-                }
-            })
-        },
+    let fields = match &input.data {
+        syn::Data::Struct(data) => &data.fields,
         syn::Data::Enum(_) => panic!("enum is not supported yet"),
         syn::Data::Union(_) => panic!("union is not supported"),
     };
 
+    let body = fields.iter().map(|field| {
+        let ident = &field.ident;
+        if field.attrs.iter().any(|attr| {
+            attr.path.segments.len() == 1
+                && attr
+                    .path
+                    .segments
+                    .first()
+                    .unwrap()
+                    .ident
+                    .to_string()
+                    .as_str()
+                    == "meta_required"
+        }) {
+            quote::quote! {
+                let field = util::meta_data::extract_field(&mut map, stringify!(#ident))
+                    .ok_or_else(|| util::meta_data::MetaError::at(
+                        mark,
+                        format!("missing required field: {}", stringify!(#ident)),
+                    ))?;
+                util::meta_data::Deserialize::deserialize_into(&mut self.#ident, state, field)
+                    .map_err(|err| err.inside(stringify!(#ident)))?;
+            }
+        } else {
+            quote::quote! {
+                if let Some(field) = util::meta_data::extract_field(&mut map, stringify!(#ident)) {
+                    util::meta_data::Deserialize::deserialize_into(&mut self.#ident, state, field)
+                        .map_err(|err| err.inside(stringify!(#ident)))?;
+                }
+            }
+        }
+    });
+
+    let ser_body = fields.iter().map(|field| {
+        let ident = &field.ident;
+        quote::quote! {
+            (
+                stringify!(#ident).to_string(),
+                util::meta_data::Serialize::serialize(&self.#ident, state)
+                    .map_err(|err| err.inside(stringify!(#ident)))?,
+            ),
+        }
+    });
+
     let result = quote::quote! {
         impl util::meta_data::Deserialize<#parser> for #name {
-            fn deserialize_into(&mut self, state: &mut #parser, node: util::meta_data::Yaml) -> Result<(), String> {
+            fn deserialize_into(
+                &mut self,
+                state: &mut #parser,
+                node: util::meta_data::Yaml,
+            ) -> Result<(), util::meta_data::MetaError> {
+                #[allow(unused_variables)]
+                let mark = util::meta_data::Mark::of(&node);
                 match node {
                     util::meta_data::Yaml::Mapping(mut map) => {
                         #(#body)*
                         Ok(())
                     }
-                    _ => Err(format!("expected mapping, got {:?}", node)),
+                    _ => Err(util::meta_data::MetaError::expected("mapping", &node)),
                 }
             }
         }
+
+        impl util::meta_data::Serialize<#parser> for #name {
+            fn serialize(
+                &self,
+                state: &#parser,
+            ) -> Result<util::meta_data::Node, util::meta_data::MetaError> {
+                Ok(util::meta_data::Node::Mapping(vec![#(#ser_body)*]))
+            }
+        }
     };
 
     TokenStream::from(result)
@@ -144,25 +180,10 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
             let enc_code = data.variants.iter().enumerate().map(|(i, v)| {
                 let ident = &v.ident;
 
-                const U8MAX: usize = u8::MAX as usize;
-                const U16MAX: usize = u16::MAX as usize;
-                const U32MAX: usize = u32::MAX as usize;
-
-
-                let i = LitInt::from(match data.variants.len() {
-                    0..=U8MAX => {
-                        Literal::u8_suffixed(i as u8)
-                    }
-                    0..=U16MAX => {
-                        Literal::u16_suffixed(i as u16)
-                    }
-                    0..=U32MAX => {
-                        Literal::u32_suffixed(i as u32)
-                    }
-                    _ => {
-                        Literal::u64_suffixed(i as u64)
-                    }
-                });
+                let i = LitInt::new(
+                    &format!("{}{}", i, discriminant_type(data.variants.len())),
+                    proc_macro::Span::call_site().into(),
+                );
 
                 let encodes = v.fields.iter().enumerate().map(|(i, f)| {
                     let ident = f
@@ -183,7 +204,7 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
                         }
                     }
                 } else {
-                    if v.fields.len() == 0 {
+                    if v.fields.is_empty() {
                         quote::quote! {
                             Self::#ident => {
                                 #i.encode(buffer);
@@ -208,6 +229,11 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
             let dec_code = data.variants.iter().enumerate().map(|(i, v)| {
                 let ident = &v.ident;
 
+                let i = LitInt::new(
+                    &format!("{}{}", i, discriminant_type(data.variants.len())),
+                    proc_macro::Span::call_site().into(),
+                );
+
                 let decodes = v.fields.iter().enumerate().map(|(i, f)| {
                     let ident = f
                         .ident
@@ -229,7 +255,7 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
                         }
                     }
                 } else {
-                    if v.fields.len() == 0 {
+                    if v.fields.is_empty() {
                         quote::quote! {
                             #i => {
                                 *self = Self::#ident;
@@ -251,6 +277,11 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
                 }
             });
 
+            let id_type = Ident::new(
+                discriminant_type(data.variants.len()),
+                proc_macro::Span::call_site().into(),
+            );
+
             quote::quote! {
                 impl Bitwise for #name {
                     fn encode(&self, buffer: &mut Vec<u8>) {
@@ -260,7 +291,7 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
                     }
 
                     fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
-                        let mut id: usize = 0;
+                        let mut id: #id_type = 0;
                         id.decode(cursor, buffer)?;
                         match id {
                             #(#dec_code)*
@@ -277,3 +308,18 @@ pub fn bitwise_derive(input: TokenStream) -> TokenStream {
 
     TokenStream::from(result)
 }
+
+fn discriminant_type(variant_count: usize) -> &'static str {
+    const U8MAX: usize = u8::MAX as usize;
+    const U16MIN: usize = U8MAX + 1;
+    const U16MAX: usize = u16::MAX as usize;
+    const U32MIN: usize = U16MAX + 1;
+    const U32MAX: usize = u32::MAX as usize;
+
+    match variant_count {
+        ..=U8MAX => "u8",
+        U16MIN..=U16MAX => "u16",
+        U32MIN..=U32MAX => "u32",
+        _ => "u64",
+    }
+}
@@ -0,0 +1,129 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Secret the server hands to a player when it joins. Udp datagrams are
+/// signed with it in both directions.
+pub type Token = u128;
+
+/// Size of the mac appended to every datagram.
+pub const MAC_SIZE: usize = std::mem::size_of::<u128>();
+
+pub fn generate_token() -> Token {
+    let mut bytes = [0; std::mem::size_of::<Token>()];
+    getrandom::getrandom(&mut bytes).expect("no source of randomness");
+    Token::from_le_bytes(bytes)
+}
+
+fn hmac(token: Token) -> Hmac<Sha256> {
+    Hmac::new_from_slice(&token.to_le_bytes()).expect("hmac accepts any key size")
+}
+
+/// Truncated HMAC-SHA256 of `bytes`.
+pub fn sign(token: Token, bytes: &[u8]) -> [u8; MAC_SIZE] {
+    let mut mac = hmac(token);
+    mac.update(bytes);
+    mac.finalize().into_bytes()[..MAC_SIZE].try_into().unwrap()
+}
+
+/// Checks the mac at the end of `signed`, in constant time.
+pub fn verify(token: Token, signed: &[u8]) -> bool {
+    let Some(split) = signed.len().checked_sub(MAC_SIZE) else {
+        return false;
+    };
+    let (bytes, tag) = signed.split_at(split);
+    let mut mac = hmac(token);
+    mac.update(bytes);
+    mac.verify_truncated_left(tag).is_ok()
+}
+
+/// Rejects datagram sequence numbers that were already accepted or are too
+/// old to tell, so captured datagrams cannot be sent again.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    // highest accepted sequence + 1, zero before the first one
+    next: u64,
+    // bit `i` is set when `next - 1 - i` was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub const SIZE: u64 = u64::BITS as u64;
+
+    /// Whether `accept` would take the sequence, without recording it.
+    pub fn is_fresh(&self, sequence: u64) -> bool {
+        if sequence == u64::MAX {
+            return false;
+        }
+        if sequence >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - sequence;
+        age < Self::SIZE && self.seen & (1 << age) == 0
+    }
+
+    /// Accepts the sequence unless it is a replay. Call only after the mac
+    /// was verified, or forged datagrams could move the window.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if !self.is_fresh(sequence) {
+            return false;
+        }
+
+        if sequence >= self.next {
+            let shift = sequence - self.next + 1;
+            self.seen = if shift >= Self::SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = sequence + 1;
+            return true;
+        }
+
+        self.seen |= 1 << (self.next - 1 - sequence);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        let token = generate_token();
+        assert_ne!(token, generate_token());
+
+        let mut signed = b"datagram".to_vec();
+        signed.extend_from_slice(&sign(token, &signed));
+        assert!(verify(token, &signed));
+        assert!(!verify(token ^ 1, &signed));
+        assert!(!verify(token, &signed[1..]));
+        assert!(!verify(token, &[0; 3]));
+
+        signed[0] ^= 1;
+        assert!(!verify(token, &signed));
+    }
+
+    #[test]
+    fn replays() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(0));
+        assert!(!window.accept(0));
+        assert!(window.is_fresh(5));
+        assert!(window.accept(5));
+        assert!(!window.is_fresh(5));
+        // late but inside the window
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        assert!(window.accept(1));
+
+        assert!(window.accept(100));
+        assert!(!window.accept(5));
+        assert!(window.accept(100 - ReplayWindow::SIZE + 1));
+        assert!(!window.accept(100 - ReplayWindow::SIZE));
+        assert!(!window.accept(100));
+        assert!(window.accept(u64::MAX - 1));
+        assert!(!window.accept(100));
+    }
+}
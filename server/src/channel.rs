@@ -0,0 +1,550 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bitwise::*;
+
+/// How a message sent over udp is delivered. Every channel has its own
+/// sequence numbers so a lost reliable message does not hold back the others.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// May be lost, duplicated or reordered.
+    #[default]
+    Unreliable,
+    /// May be lost, anything older than the last delivered message is dropped.
+    UnreliableSequenced,
+    /// Delivered exactly once, in any order.
+    ReliableUnordered,
+    /// Delivered exactly once, in the order it was sent.
+    ReliableOrdered,
+}
+
+impl Channel {
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Self::ReliableUnordered | Self::ReliableOrdered)
+    }
+}
+
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub struct Message {
+    pub channel: Channel,
+    pub sequence: u32,
+    pub data: Vec<u8>,
+}
+
+/// Contents of one datagram.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub struct Frame {
+    pub unordered_acks: Vec<u32>,
+    pub ordered_acks: Vec<u32>,
+    pub messages: Vec<Message>,
+}
+
+impl Frame {
+    pub fn is_empty(&self) -> bool {
+        self.unordered_acks.is_empty() && self.ordered_acks.is_empty() && self.messages.is_empty()
+    }
+}
+
+struct Pending {
+    message: Message,
+    // when the message first went out, it ages from there
+    first_sent: Option<Instant>,
+    sent: Option<Instant>,
+}
+
+/// `Connection::send` refused a message, `Connection::MAX_PENDING` reliable
+/// ones wait for an ack already.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Congested;
+
+impl std::fmt::Display for Congested {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many reliable messages wait for an ack")
+    }
+}
+
+impl std::error::Error for Congested {}
+
+/// Channel state of one end of a udp link. It does not touch sockets,
+/// frames go out through `flush` and come in through `receive`.
+pub struct Connection {
+    resend_after: Duration,
+    next_sequence: [u32; 4],
+    outgoing: Vec<Message>,
+    pending: Vec<Pending>,
+    unordered_acks: Vec<u32>,
+    ordered_acks: Vec<u32>,
+
+    last_sequenced: Option<u32>,
+    // everything below the base was delivered, the set holds the rest
+    unordered_base: u32,
+    unordered_seen: BTreeSet<u32>,
+    next_ordered: u32,
+    ordered_buffer: BTreeMap<u32, Message>,
+    received: VecDeque<Message>,
+}
+
+impl Connection {
+    pub const DEFAULT_RESEND: Duration = Duration::from_millis(100);
+    /// Messages are packed into frames up to this size, a bigger message
+    /// gets a frame of its own. Nothing is fragmented, so peers drop frames
+    /// with messages above this size, see `MAX_FRAME_SIZE`.
+    pub const MAX_FRAME_DATA: usize = 1200;
+    /// Acks of each kind sent in one frame, the rest wait for the next.
+    pub const MAX_ACKS: usize = 64;
+    /// Messages packed into one frame.
+    pub const MAX_MESSAGES: usize = 64;
+    /// Reliable messages this far ahead of the oldest undelivered one are
+    /// dropped unacked and resent later, which bounds what a peer can make
+    /// the receiver keep.
+    pub const WINDOW: u32 = 1024;
+    /// Reliable messages kept until acked, `send` refuses more. Unreliable
+    /// ones above it are dropped while they wait for a `flush`. Matches the
+    /// receive window, so nothing sent is too far ahead for the peer.
+    pub const MAX_PENDING: usize = Self::WINDOW as usize;
+    /// What a message adds to the frame besides its data, at most.
+    const MESSAGE_OVERHEAD: usize = 16;
+    /// Encoded size of the biggest frame `flush` sends when no message is
+    /// bigger than `MAX_FRAME_DATA`.
+    pub const MAX_FRAME_SIZE: usize = 3 * std::mem::size_of::<usize>()
+        + 2 * Self::MAX_ACKS * std::mem::size_of::<u32>()
+        + Self::MAX_MESSAGES * Self::MESSAGE_OVERHEAD
+        + Self::MAX_FRAME_DATA;
+
+    pub fn new() -> Self {
+        Self::with_resend(Self::DEFAULT_RESEND)
+    }
+
+    pub fn with_resend(resend_after: Duration) -> Self {
+        Self {
+            resend_after,
+            next_sequence: [0; 4],
+            outgoing: vec![],
+            pending: vec![],
+            unordered_acks: vec![],
+            ordered_acks: vec![],
+            last_sequenced: None,
+            unordered_base: 0,
+            unordered_seen: BTreeSet::new(),
+            next_ordered: 0,
+            ordered_buffer: BTreeMap::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Queues the message, it is sent by the next `flush`. A peer that does
+    /// not ack makes reliable channels fail once `MAX_PENDING` messages wait.
+    pub fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), Congested> {
+        if channel.is_reliable() && self.pending.len() >= Self::MAX_PENDING {
+            return Err(Congested);
+        }
+        if !channel.is_reliable() && self.outgoing.len() >= Self::MAX_PENDING {
+            return Ok(());
+        }
+
+        let sequence = &mut self.next_sequence[channel as usize];
+        let message = Message {
+            channel,
+            sequence: *sequence,
+            data: data.to_vec(),
+        };
+        *sequence = sequence.wrapping_add(1);
+
+        if channel.is_reliable() {
+            self.pending.push(Pending {
+                message,
+                first_sent: None,
+                sent: None,
+            });
+        } else {
+            self.outgoing.push(message);
+        }
+        Ok(())
+    }
+
+    /// Reliable messages that were not acknowledged yet.
+    pub fn unacked(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// How long the oldest unacked message has been out, zero when there is
+    /// none or it was not sent yet.
+    pub fn unacked_for(&self, now: Instant) -> Duration {
+        self.pending
+            .iter()
+            .filter_map(|pending| pending.first_sent)
+            .min()
+            .map_or(Duration::ZERO, |sent| now.saturating_duration_since(sent))
+    }
+
+    /// Sends queued messages, resends reliable ones that were not acked in
+    /// time and acknowledges what was received.
+    pub fn flush(&mut self, now: Instant, mut send: impl FnMut(&Frame)) {
+        let acks = |acks: &mut Vec<u32>| acks.drain(..acks.len().min(Self::MAX_ACKS)).collect();
+        let mut frame = Frame {
+            unordered_acks: acks(&mut self.unordered_acks),
+            ordered_acks: acks(&mut self.ordered_acks),
+            messages: vec![],
+        };
+        let mut size = 0;
+        let mut push = |frame: &mut Frame, message: Message| {
+            let full = size + message.data.len() > Self::MAX_FRAME_DATA
+                || frame.messages.len() == Self::MAX_MESSAGES;
+            if full && !frame.messages.is_empty() {
+                send(frame);
+                frame.unordered_acks.clear();
+                frame.ordered_acks.clear();
+                frame.messages.clear();
+                size = 0;
+            }
+            size += message.data.len();
+            frame.messages.push(message);
+        };
+
+        for message in self.outgoing.drain(..) {
+            push(&mut frame, message);
+        }
+
+        for pending in &mut self.pending {
+            let due = pending
+                .sent
+                .is_none_or(|sent| now.duration_since(sent) >= self.resend_after);
+            if due {
+                pending.first_sent.get_or_insert(now);
+                pending.sent = Some(now);
+                push(&mut frame, pending.message.clone());
+            }
+        }
+
+        if !frame.is_empty() {
+            send(&frame);
+        }
+    }
+
+    /// Processes a received frame, delivered messages are returned by `recv`.
+    pub fn receive(&mut self, frame: Frame) {
+        self.pending.retain(|pending| {
+            let acks = match pending.message.channel {
+                Channel::ReliableUnordered => &frame.unordered_acks,
+                _ => &frame.ordered_acks,
+            };
+            !acks.contains(&pending.message.sequence)
+        });
+
+        for message in frame.messages {
+            let sequence = message.sequence;
+            match message.channel {
+                Channel::Unreliable => self.received.push_back(message),
+                Channel::UnreliableSequenced => {
+                    if self
+                        .last_sequenced
+                        .is_none_or(|last| is_newer(sequence, last))
+                    {
+                        self.last_sequenced = Some(sequence);
+                        self.received.push_back(message);
+                    }
+                }
+                Channel::ReliableUnordered => {
+                    let Some(fresh) = in_window(sequence, self.unordered_base) else {
+                        continue;
+                    };
+                    // duplicates are acked again, the first ack may be lost
+                    self.unordered_acks.push(sequence);
+                    if !fresh || !self.unordered_seen.insert(sequence) {
+                        continue;
+                    }
+                    while self.unordered_seen.remove(&self.unordered_base) {
+                        self.unordered_base = self.unordered_base.wrapping_add(1);
+                    }
+                    self.received.push_back(message);
+                }
+                Channel::ReliableOrdered => {
+                    let Some(fresh) = in_window(sequence, self.next_ordered) else {
+                        continue;
+                    };
+                    self.ordered_acks.push(sequence);
+                    if !fresh {
+                        continue;
+                    }
+                    self.ordered_buffer.insert(sequence, message);
+                    while let Some(message) = self.ordered_buffer.remove(&self.next_ordered) {
+                        self.next_ordered = self.next_ordered.wrapping_add(1);
+                        self.received.push_back(message);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn recv(&mut self) -> Option<Message> {
+        self.received.pop_front()
+    }
+}
+
+/// Sequences wrap, the one less than half the range ahead is newer.
+fn is_newer(sequence: u32, than: u32) -> bool {
+    (sequence.wrapping_sub(than) as i32) > 0
+}
+
+/// Whether a reliable message at `sequence` may be delivered given the
+/// oldest undelivered one at `base`, `None` when it is too far ahead to be
+/// kept. Anything behind was delivered already.
+fn in_window(sequence: u32, base: u32) -> Option<bool> {
+    let ahead = sequence.wrapping_sub(base);
+    match ahead as i32 {
+        ..0 => Some(false),
+        _ if ahead < Connection::WINDOW => Some(true),
+        _ => None,
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, time::Duration};
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    /// Frames in flight, lost, duplicated and reordered at random.
+    struct LossyLink {
+        rng: ChaCha8Rng,
+        in_flight: Vec<Vec<u8>>,
+    }
+
+    impl LossyLink {
+        fn send(&mut self, frame: &Frame) {
+            let mut bytes = vec![];
+            frame.encode(&mut bytes);
+            if self.rng.gen_bool(0.3) {
+                return;
+            }
+            if self.rng.gen_bool(0.1) {
+                self.in_flight.push(bytes.clone());
+            }
+            self.in_flight.push(bytes);
+        }
+
+        fn deliver(&mut self, to: &mut Connection) {
+            while !self.in_flight.is_empty() {
+                let frame = self
+                    .in_flight
+                    .swap_remove(self.rng.gen_range(0..self.in_flight.len()));
+                let mut decoded = Frame::default();
+                decoded.decode(&mut 0, &frame).unwrap();
+                to.receive(decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn lossy_link() {
+        const MESSAGES: u32 = 300;
+        let channels = [
+            Channel::Unreliable,
+            Channel::UnreliableSequenced,
+            Channel::ReliableUnordered,
+            Channel::ReliableOrdered,
+        ];
+
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        let mut forward = LossyLink {
+            rng: ChaCha8Rng::seed_from_u64(1),
+            in_flight: vec![],
+        };
+        let mut backward = LossyLink {
+            rng: ChaCha8Rng::seed_from_u64(2),
+            in_flight: vec![],
+        };
+
+        let mut received = vec![vec![]; 4];
+        let mut now = Instant::now();
+        for tick in 0..1000 {
+            if tick < MESSAGES {
+                for channel in channels {
+                    sender.send(channel, &tick.to_le_bytes()).unwrap();
+                }
+            }
+
+            sender.flush(now, |frame| forward.send(frame));
+            forward.deliver(&mut receiver);
+            receiver.flush(now, |frame| backward.send(frame));
+            backward.deliver(&mut sender);
+            while let Some(message) = receiver.recv() {
+                let value = u32::from_le_bytes(message.data.try_into().unwrap());
+                received[message.channel as usize].push(value);
+            }
+
+            if tick >= MESSAGES && sender.unacked() == 0 {
+                break;
+            }
+            now += Duration::from_millis(50);
+        }
+        assert_eq!(sender.unacked(), 0);
+
+        let [unreliable, sequenced, unordered, ordered] = &received[..] else {
+            unreachable!()
+        };
+        assert!(unreliable.len() < MESSAGES as usize);
+        assert!(unreliable.iter().all(|&value| value < MESSAGES));
+        assert!(sequenced.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!sequenced.is_empty() && sequenced.len() < MESSAGES as usize);
+
+        assert_eq!(unordered.len(), MESSAGES as usize);
+        assert_eq!(
+            unordered.iter().collect::<HashSet<_>>().len(),
+            MESSAGES as usize
+        );
+        assert_ne!(unordered, ordered, "the link should reorder");
+        assert_eq!(*ordered, (0..MESSAGES).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn frames_split() {
+        let mut connection = Connection::new();
+        for _ in 0..3 {
+            connection
+                .send(
+                    Channel::ReliableOrdered,
+                    &[0; Connection::MAX_FRAME_DATA / 2],
+                )
+                .unwrap();
+        }
+        connection
+            .send(Channel::Unreliable, &[0; Connection::MAX_FRAME_DATA * 2])
+            .unwrap();
+
+        let now = Instant::now();
+        let mut frames = vec![];
+        connection.flush(now, |frame| frames.push(frame.messages.len()));
+        assert_eq!(frames, [1, 2, 1]);
+
+        // nothing is resent before the timeout
+        connection.flush(now + Duration::from_millis(10), |_| panic!());
+        let mut resent = 0;
+        connection.flush(now + Connection::DEFAULT_RESEND, |frame| {
+            resent += frame.messages.len()
+        });
+        assert_eq!(resent, 3);
+    }
+
+    #[test]
+    fn frames_stay_small() {
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        for _ in 0..200 {
+            sender.send(Channel::ReliableUnordered, &[]).unwrap();
+        }
+        let mut frames = vec![];
+        sender.flush(Instant::now(), |frame| frames.push(frame.clone()));
+        assert_eq!(frames.len(), 200_usize.div_ceil(Connection::MAX_MESSAGES));
+
+        for frame in frames {
+            receiver.receive(frame);
+        }
+        // the rest of the acks wait for the next flush
+        let mut acks = vec![];
+        for _ in 0..4 {
+            receiver.flush(Instant::now(), |frame| {
+                let mut data = vec![];
+                frame.encode(&mut data);
+                assert!(data.len() <= Connection::MAX_FRAME_SIZE);
+                acks.push(frame.unordered_acks.len());
+            });
+        }
+        assert_eq!(acks, [64, 64, 64, 8]);
+    }
+
+    #[test]
+    fn sequences_wrap() {
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        let start = u32::MAX - 2;
+        sender.next_sequence = [start; 4];
+        receiver.unordered_base = start;
+        receiver.next_ordered = start;
+        receiver.last_sequenced = Some(start.wrapping_sub(1));
+
+        for i in 0..6u8 {
+            for channel in [Channel::UnreliableSequenced, Channel::ReliableOrdered] {
+                sender.send(channel, &[i]).unwrap();
+            }
+            sender.send(Channel::ReliableUnordered, &[i]).unwrap();
+        }
+        sender.flush(Instant::now(), |frame| receiver.receive(frame.clone()));
+        let mut received = vec![vec![]; 4];
+        while let Some(message) = receiver.recv() {
+            received[message.channel as usize].push(message.data[0]);
+        }
+        for channel in [1, 2, 3] {
+            assert_eq!(received[channel], [0, 1, 2, 3, 4, 5]);
+        }
+        assert!(receiver.unordered_seen.is_empty());
+        assert_eq!(receiver.next_ordered, 3);
+
+        // acks cover everything, so nothing is left to resend
+        receiver.flush(Instant::now(), |frame| sender.receive(frame.clone()));
+        assert_eq!(sender.unacked(), 0);
+    }
+
+    #[test]
+    fn far_ahead_is_not_kept() {
+        let mut receiver = Connection::new();
+        let far = |channel| Message {
+            channel,
+            sequence: Connection::WINDOW,
+            data: vec![],
+        };
+        receiver.receive(Frame {
+            messages: vec![
+                far(Channel::ReliableUnordered),
+                far(Channel::ReliableOrdered),
+            ],
+            ..Default::default()
+        });
+        assert!(receiver.unordered_seen.is_empty());
+        assert!(receiver.ordered_buffer.is_empty());
+        // unacked, so the sender resends once the window moved on
+        receiver.flush(Instant::now(), |_| panic!("nothing to ack"));
+    }
+
+    #[test]
+    fn never_acked() {
+        let mut sender = Connection::new();
+        let start = Instant::now();
+        for i in 0..Connection::MAX_PENDING {
+            let channel = [Channel::ReliableUnordered, Channel::ReliableOrdered][i % 2];
+            sender.send(channel, &[]).unwrap();
+        }
+        assert_eq!(sender.send(Channel::ReliableOrdered, &[]), Err(Congested));
+        // losing unreliable messages is fine
+        assert_eq!(sender.send(Channel::Unreliable, &[]), Ok(()));
+        assert_eq!(sender.unacked_for(start), Duration::ZERO);
+
+        // the receiver drops everything, resends do not reset the age
+        let mut now = start;
+        for _ in 0..10 {
+            sender.flush(now, |_| {});
+            now += Connection::DEFAULT_RESEND;
+        }
+        assert_eq!(sender.unacked(), Connection::MAX_PENDING);
+        assert_eq!(sender.unacked_for(now), Connection::DEFAULT_RESEND * 10);
+
+        // the first ack makes room again
+        let mut receiver = Connection::new();
+        sender.flush(now, |frame| receiver.receive(frame.clone()));
+        receiver.flush(now, |frame| sender.receive(frame.clone()));
+        assert!(sender.unacked() < Connection::MAX_PENDING);
+        assert!(sender.send(Channel::ReliableOrdered, &[]).is_ok());
+    }
+}
@@ -1,52 +1,678 @@
 use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream, UdpSocket, ToSocketAddrs},
-    time::Duration,
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
 };
 
 use bitwise::{Bitwise, Decoder, Encoder};
+use store::Invalid;
 
-use crate::protocol::{JoinRequestData, self, JoinInfo, Player};
+use crate::auth::ReplayWindow;
+use crate::channel::{Channel, Connection};
+use crate::crypto::{Cipher, Handshake, Side};
+use crate::link::{DatagramSocket, Direct, Network, StreamSocket};
+use crate::protocol::{
+    self, BanScope, DatagramHeader, JoinInfo, JoinPolicy, JoinRequestData, Packet, Player,
+    Rejection, ServerInfo, ServerMessage, ServerPacket, Session, SessionFilter, SessionListing,
+    DISCOVERY_GROUP, DISCOVERY_PORT, DISCOVERY_PROBE_SIZE, DISCOVER_OC, FIRST_RESERVED_OC,
+    INVITE_OC, JOIN_POLICY_OC, JOIN_REQUEST_OC, KEY_EXCHANGE_OC, KICK_REQUEST_OC, LIST_SESSIONS_OC,
+    MAX_TCP_FRAME, MESSAGE_OC, MUTE_OC, TRANSFER_OWNER_OC, UDP_REGISTER_OC,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Packet sent by another player.
+    Packet(ServerPacket),
+    /// Sent by the server itself, joins, kicks and errors are reported this
+    /// way.
+    Message(ServerMessage),
+}
+
+/// The reason behind an error returned when the server refused to let the
+/// client in.
+pub fn rejection(error: &Error) -> Option<Rejection> {
+    error.get_ref()?.downcast_ref::<Rejection>().copied()
+}
+
+/// Server that answered a discovery probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub ip: IpAddr,
+    pub info: ServerInfo,
+}
+
+/// Probes the local network on `DISCOVERY_PORT` and collects the answers
+/// arriving within `timeout`.
+pub fn discover(timeout: Duration) -> std::io::Result<Vec<DiscoveredServer>> {
+    discover_on(DISCOVERY_PORT, timeout)
+}
+
+/// Like `discover`, for servers answering on another port.
+pub fn discover_on(port: u16, timeout: Duration) -> std::io::Result<Vec<DiscoveredServer>> {
+    let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    udp.set_broadcast(true)?;
+
+    let mut encoder = Encoder::new();
+    encoder.encode(&DISCOVER_OC);
+    let mut probe = encoder.data().to_vec();
+    probe.resize(DISCOVERY_PROBE_SIZE, 0);
+    // broadcasts do not loop back everywhere, so this host is asked directly
+    let targets = [Ipv4Addr::BROADCAST, DISCOVERY_GROUP, Ipv4Addr::LOCALHOST];
+    let mut sent = false;
+    let mut last_error = None;
+    for ip in targets {
+        match udp.send_to(&probe, (ip, port)) {
+            Ok(_) => sent = true,
+            // networks without a route for one of them are common
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if let (false, Some(e)) = (sent, last_error) {
+        return Err(e);
+    }
+
+    let mut servers = Vec::<DiscoveredServer>::new();
+    let mut buffer = [0; 2048];
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(servers);
+        }
+        udp.set_read_timeout(Some(deadline - now))?;
+        let (received, addr) = match udp.recv_from(&mut buffer) {
+            Ok(answer) => answer,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(servers)
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e),
+        };
+
+        let Some(packet) = buffer
+            .get(Encoder::LEN_SIZE..received)
+            .and_then(decode_data::<ServerPacket>)
+        else {
+            continue;
+        };
+        if packet.op_code != DISCOVER_OC {
+            continue;
+        }
+        let Some(info) = decode_data::<ServerInfo>(&packet.data) else {
+            continue;
+        };
+
+        // the same server may answer more than one probe
+        let server = DiscoveredServer {
+            ip: addr.ip(),
+            info,
+        };
+        if !servers
+            .iter()
+            .any(|found| found.ip == server.ip && found.info.port == server.info.port)
+        {
+            servers.push(server);
+        }
+    }
+}
 
 pub struct Client {
-    tcp: TcpStream,
-    udp: UdpSocket,
+    tcp: Box<dyn StreamSocket>,
+    udp: Box<dyn DatagramSocket>,
     encoder: Encoder,
     decoder: Decoder,
+    packet: Packet,
+    connection: Connection,
+    // tcp is nonblocking after joining so frames can arrive in pieces
+    tcp_buffer: Vec<u8>,
+    // frames the socket did not take yet, they go out before anything newer
+    tcp_queue: Vec<u8>,
+    events: VecDeque<Event>,
     udp_addr: SocketAddr,
     join_info: JoinInfo,
+    replay: ReplayWindow,
+    // sequence of the next datagram sent to the server
+    sequence: u64,
+    cipher: Option<Cipher>,
 }
 
 impl Client {
+    const TIMEOUT: Duration = Duration::from_secs(3);
+    const REGISTER_RETRY: Duration = Duration::from_millis(100);
+
+    /// Joins or creates a session and registers the udp address, blocks
+    /// until the server confirms both.
     pub fn new(ip: &str, port: u16, join_request_data: JoinRequestData) -> std::io::Result<Self> {
-        let mut tcp = TcpStream::connect((ip, port))?;
-        let mut udp = UdpSocket::bind((ip, port))?;
+        Self::with_network(ip, port, join_request_data, &Direct, false)
+    }
+
+    /// Like `new`, but keys are exchanged first and everything after that,
+    /// the password included, is encrypted. The server is not
+    /// authenticated, this protects against passive eavesdroppers only, see
+    /// `crypto::Handshake`.
+    pub fn encrypted(
+        ip: &str,
+        port: u16,
+        join_request_data: JoinRequestData,
+    ) -> std::io::Result<Self> {
+        Self::with_network(ip, port, join_request_data, &Direct, true)
+    }
+
+    /// Like `new`, with both sockets going through `network`.
+    pub fn with_network(
+        ip: &str,
+        port: u16,
+        join_request_data: JoinRequestData,
+        network: &dyn Network,
+        encrypt: bool,
+    ) -> std::io::Result<Self> {
+        let tcp = TcpStream::connect((ip, port))?;
+        tcp.set_nodelay(true)?;
+        let mut tcp = network.stream(tcp)?;
+        tcp.set_read_timeout(Some(Self::TIMEOUT))?;
 
         let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let mut cipher = match encrypt {
+            true => Some(exchange_keys(&mut *tcp, &mut encoder, &mut decoder)?),
+            false => None,
+        };
+
+        encoder.encode(&JOIN_REQUEST_OC);
         encoder.encode(&join_request_data);
+        write_frame(&mut *tcp, &mut encoder, cipher.as_mut())?;
 
-        tcp.set_read_timeout(Some(Duration::new(3, 0)))?;
-        tcp.write(encoder.data())?;
+        protocol::read_tcp_packet_bytes(&mut *tcp, &mut decoder)?;
+        open_frame(&mut decoder, cipher.as_mut())?;
+        let response: ServerPacket = decoder
+            .decode()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Failed to parse join data."))?;
+        let join_info = match response.op_code {
+            MESSAGE_OC => match decode_data(&response.data) {
+                Some(ServerMessage::PlayerJoined(join_info)) => join_info,
+                _ => return Err(message_error(&response)),
+            },
+            op_code => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected op code {} when joining.", op_code),
+                ))
+            }
+        };
         tcp.set_read_timeout(None)?;
+        tcp.set_nonblocking(true)?;
+
+        let udp_addr = SocketAddr::new(tcp.peer_addr()?.ip(), join_info.udp_port);
+        let udp = network.datagram(UdpSocket::bind((tcp.local_addr()?.ip(), 0))?)?;
+
+        let mut client = Self {
+            tcp,
+            udp,
+            encoder,
+            decoder,
+            packet: Packet::default(),
+            connection: Connection::new(),
+            tcp_buffer: vec![],
+            tcp_queue: vec![],
+            events: VecDeque::new(),
+            udp_addr,
+            join_info,
+            replay: ReplayWindow::default(),
+            sequence: 0,
+            cipher,
+        };
+        client.register_udp()?;
+        Ok(client)
+    }
+
+    /// Public sessions passing every filter, private ones are never listed.
+    pub fn list_sessions(
+        ip: &str,
+        port: u16,
+        filters: &[SessionFilter],
+    ) -> std::io::Result<Vec<SessionListing>> {
+        let mut tcp = TcpStream::connect((ip, port))?;
+        tcp.set_read_timeout(Some(Self::TIMEOUT))?;
+
+        let mut encoder = Encoder::new();
+        encoder.encode(&LIST_SESSIONS_OC);
+        encoder.encode(&filters.to_vec());
+        write_frame(&mut tcp, &mut encoder, None)?;
 
         let mut decoder = Decoder::new();
         protocol::read_tcp_packet_bytes(&mut tcp, &mut decoder)?;
-        let join_info: JoinInfo = decoder.decode()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse join data."))?;
+        let response: ServerPacket = decoder
+            .decode()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Failed to parse listings."))?;
+        match response.op_code {
+            LIST_SESSIONS_OC => decode_data(&response.data)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Failed to parse listings.")),
+            MESSAGE_OC => Err(message_error(&response)),
+            op_code => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected op code {} when listing sessions.", op_code),
+            )),
+        }
+    }
 
-        let udp_addr = SocketAddr::new(ip.parse().unwrap(), join_info.udp_port); 
-        encoder.clear();
-        encoder.encode()
-        udp.send_to(buf, addr)
+    pub fn join_info(&self) -> &JoinInfo {
+        &self.join_info
+    }
 
+    pub fn id(&self) -> Player {
+        self.join_info.joined
+    }
 
-        Ok(Self {
-            tcp,
+    pub fn session(&self) -> Session {
+        self.join_info.session
+    }
+
+    /// Follows `ServerMessage::OwnerChanged` as it is polled.
+    pub fn owner(&self) -> Player {
+        self.join_info.owner
+    }
+
+    /// Sends over tcp, empty `targets` means everyone else in the session.
+    pub fn send_reliable(
+        &mut self,
+        op_code: u32,
+        targets: &[Player],
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        check_op_code(op_code)?;
+        self.encode_packet(op_code, true, targets, data);
+        self.send_tcp()
+    }
+
+    /// Sends over udp, the packet may be lost, duplicated or reordered.
+    pub fn send_unreliable(
+        &mut self,
+        op_code: u32,
+        targets: &[Player],
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        self.send(Channel::Unreliable, op_code, targets, data)
+    }
+
+    /// Sends over udp with the guarantees of `channel`. The server delivers
+    /// to each target through the same channel. Nothing is fragmented, the
+    /// encoded packet has to fit `Connection::MAX_FRAME_DATA`. Reliable
+    /// channels fail with `WouldBlock` while `Connection::MAX_PENDING`
+    /// messages wait for an ack.
+    pub fn send(
+        &mut self,
+        channel: Channel,
+        op_code: u32,
+        targets: &[Player],
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        check_op_code(op_code)?;
+        self.send_udp(channel, op_code, targets, data)
+    }
+
+    /// Returns the next event without blocking. An error means the
+    /// connection is broken, the server closing it included. Lost reliable
+    /// messages are resent and queued tcp frames sent from here, so it has
+    /// to be called regularly.
+    pub fn poll(&mut self) -> std::io::Result<Option<Event>> {
+        self.flush_udp()?;
+        protocol::flush_tcp(&mut *self.tcp, &mut self.tcp_queue)?;
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+
+        while let Some(packet) = self.recv_tcp()? {
+            if let Some(event) = self.handle(packet) {
+                return Ok(Some(event));
+            }
+        }
+
+        self.recv_udp()?;
+        while let Some(message) = self.connection.recv() {
+            let Some(packet) = decode_data(&message.data) else {
+                continue;
+            };
+            if let Some(event) = self.handle(packet) {
+                return Ok(Some(event));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Only the session owner may kick, others get an error back.
+    pub fn kick(&mut self, target: Player) -> std::io::Result<()> {
+        self.ban(target, BanScope::None)
+    }
+
+    /// Kicks the target and refuses what `scope` says from then on. An
+    /// address ban locks out everyone sharing the address, players behind
+    /// the same NAT or on the same machine included, so prefer `BanScope::Id`
+    /// unless the target changes its client id.
+    pub fn ban(&mut self, target: Player, scope: BanScope) -> std::io::Result<()> {
+        self.send_request(KICK_REQUEST_OC, &[target], &scope)
+    }
+
+    /// Stops or resumes forwarding of everything the targets send, only the
+    /// owner may do it.
+    pub fn mute(&mut self, targets: &[Player], muted: bool) -> std::io::Result<()> {
+        self.send_request(MUTE_OC, targets, &muted)
+    }
+
+    /// Only the owner may change who can join.
+    pub fn set_join_policy(&mut self, policy: JoinPolicy) -> std::io::Result<()> {
+        self.send_request(JOIN_POLICY_OC, &[], &policy)
+    }
+
+    /// Asks for a single use invite code, it arrives as
+    /// `ServerMessage::Invite`.
+    pub fn invite(&mut self) -> std::io::Result<()> {
+        self.encode_packet(INVITE_OC, true, &[], &[]);
+        self.send_tcp()
+    }
+
+    /// Hands the session over, only the owner may do it.
+    pub fn transfer_ownership(&mut self, target: Player) -> std::io::Result<()> {
+        self.encode_packet(TRANSFER_OWNER_OC, true, &[target], &[]);
+        self.send_tcp()
+    }
+
+    pub fn disconnect(self) -> std::io::Result<()> {
+        self.tcp.shutdown(Shutdown::Both)
+    }
+
+    fn register_udp(&mut self) -> std::io::Result<()> {
+        let deadline = Instant::now() + Self::TIMEOUT;
+        while Instant::now() < deadline {
+            // the server confirms over tcp, the datagram itself may be lost
+            self.send_udp(Channel::Unreliable, UDP_REGISTER_OC, &[], &[])?;
+
+            let retry = Instant::now() + Self::REGISTER_RETRY;
+            while Instant::now() < retry {
+                match self.recv_tcp()? {
+                    Some(packet) if packet.op_code == UDP_REGISTER_OC => return Ok(()),
+                    Some(packet) => {
+                        if let Some(event) = self.handle(packet) {
+                            self.events.push_back(event);
+                        }
+                    }
+                    None => thread::sleep(Duration::from_millis(1)),
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "Server did not confirm udp address.",
+        ))
+    }
+
+    fn send_request(
+        &mut self,
+        op_code: u32,
+        targets: &[Player],
+        data: &impl Bitwise,
+    ) -> std::io::Result<()> {
+        let mut bytes = vec![];
+        data.encode(&mut bytes);
+        self.encode_packet(op_code, true, targets, &bytes);
+        self.send_tcp()
+    }
+
+    fn send_tcp(&mut self) -> std::io::Result<()> {
+        let frame = match &mut self.cipher {
+            Some(cipher) => cipher.seal_frame(&self.encoder.data()[Encoder::LEN_SIZE..]),
+            None => self.encoder.data(),
+        };
+        self.tcp_queue.extend_from_slice(frame);
+        self.encoder.clear();
+        protocol::flush_tcp(&mut *self.tcp, &mut self.tcp_queue)
+    }
+
+    fn send_udp(
+        &mut self,
+        channel: Channel,
+        op_code: u32,
+        targets: &[Player],
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        self.encode_packet(op_code, false, targets, data);
+        if self.encoder.data.len() - Encoder::LEN_SIZE > Connection::MAX_FRAME_DATA {
+            self.encoder.clear();
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is too big for a datagram, send it reliably.",
+            ));
+        }
+        let sent = self
+            .connection
+            .send(channel, &self.encoder.data[Encoder::LEN_SIZE..]);
+        self.encoder.clear();
+        // polling takes acks in, after which the message can be sent again
+        sent.map_err(|e| Error::new(ErrorKind::WouldBlock, e))?;
+        self.flush_udp()
+    }
+
+    fn flush_udp(&mut self) -> std::io::Result<()> {
+        let Self {
             udp,
             encoder,
-            decoder,
+            connection,
             udp_addr,
             join_info,
-        })
+            sequence,
+            cipher,
+            ..
+        } = self;
+        let mut result = Ok(());
+        connection.flush(Instant::now(), |frame| {
+            let header = DatagramHeader {
+                session: join_info.session,
+                source: join_info.joined,
+                sequence: *sequence,
+            };
+            *sequence += 1;
+            match cipher {
+                Some(cipher) => header.encode_sealed(encoder, frame, cipher),
+                None => header.encode_signed(encoder, frame, join_info.token),
+            }
+            if let Err(e) = udp.send_to(encoder.data(), *udp_addr) {
+                result = Err(e);
+            }
+            encoder.clear();
+        });
+        result
+    }
+
+    fn encode_packet(&mut self, op_code: u32, tcp: bool, targets: &[Player], data: &[u8]) {
+        self.packet.clear();
+        self.packet.op_code = op_code;
+        self.packet.session = self.join_info.session;
+        self.packet.source = self.join_info.joined;
+        self.packet.tcp = tcp;
+        self.packet.targets.extend_from_slice(targets);
+        self.packet.data.extend_from_slice(data);
+        self.encoder.encode(&self.packet);
+    }
+
+    fn handle(&mut self, packet: ServerPacket) -> Option<Event> {
+        match packet.op_code {
+            MESSAGE_OC => {
+                let message = decode_data(&packet.data)?;
+                if let ServerMessage::OwnerChanged(owner) = message {
+                    self.join_info.owner = owner;
+                }
+                Some(Event::Message(message))
+            }
+            // late confirmation of a resent registration
+            UDP_REGISTER_OC => None,
+            _ => Some(Event::Packet(packet)),
+        }
+    }
+
+    fn recv_tcp(&mut self) -> std::io::Result<Option<ServerPacket>> {
+        if !self.has_tcp_frame() {
+            let mut chunk = [0; 1024];
+            loop {
+                match self.tcp.read(&mut chunk) {
+                    // frames sent before closing, like the kick reason, come first
+                    Ok(0) if self.has_tcp_frame() => break,
+                    Ok(0) => {
+                        return Err(Error::new(
+                            ErrorKind::ConnectionAborted,
+                            "Server closed the connection.",
+                        ))
+                    }
+                    Ok(read) => self.tcp_buffer.extend_from_slice(&chunk[..read]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+                if self.tcp_buffer.len() >= Encoder::LEN_SIZE && self.frame_size() > MAX_TCP_FRAME {
+                    return Err(protocol::oversized(self.frame_size(), MAX_TCP_FRAME));
+                }
+            }
+
+            if !self.has_tcp_frame() {
+                return Ok(None);
+            }
+        }
+
+        let size = self.frame_size();
+        self.decoder
+            .expose(size)
+            .copy_from_slice(&self.tcp_buffer[Encoder::LEN_SIZE..Encoder::LEN_SIZE + size]);
+        self.tcp_buffer.drain(..Encoder::LEN_SIZE + size);
+        open_frame(&mut self.decoder, self.cipher.as_mut())?;
+        self.decoder
+            .decode()
+            .map(Some)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Server sent garbage."))
+    }
+
+    fn has_tcp_frame(&self) -> bool {
+        self.tcp_buffer.len() >= Encoder::LEN_SIZE
+            && self.tcp_buffer.len() >= Encoder::LEN_SIZE + self.frame_size()
+    }
+
+    fn frame_size(&self) -> usize {
+        u32::from_le_bytes(self.tcp_buffer[..Encoder::LEN_SIZE].try_into().unwrap()) as usize
+    }
+
+    /// Feeds every waiting datagram to the connection.
+    fn recv_udp(&mut self) -> std::io::Result<()> {
+        loop {
+            let addr = match protocol::read_udp_packet_bytes(&mut *self.udp, &mut self.decoder) {
+                Ok(addr) => addr,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                // windows reports unreachable peers of earlier sends here
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+
+            if addr != self.udp_addr {
+                continue;
+            }
+
+            self.decoder.decode::<u32>();
+            let Some(header) = self.decoder.decode::<DatagramHeader>() else {
+                continue;
+            };
+            // both directions are signed with the same token, so a datagram
+            // of this client reflected back would pass the check otherwise
+            if header.session != self.join_info.session || header.source != Player::invalid() {
+                continue;
+            }
+            let frame = match &self.cipher {
+                Some(cipher) => header.open_frame(&mut self.decoder, cipher),
+                None => DatagramHeader::decode_frame(&mut self.decoder, self.join_info.token),
+            };
+            let Some(frame) = frame else {
+                continue;
+            };
+            if self.replay.accept(header.sequence) {
+                self.connection.receive(frame);
+            }
+        }
+    }
+}
+
+fn exchange_keys(
+    tcp: &mut dyn StreamSocket,
+    encoder: &mut Encoder,
+    decoder: &mut Decoder,
+) -> std::io::Result<Cipher> {
+    let handshake = Handshake::new();
+    encoder.encode(&KEY_EXCHANGE_OC);
+    encoder.encode(&handshake.public_key());
+    write_frame(tcp, encoder, None)?;
+
+    protocol::read_tcp_packet_bytes(tcp, decoder)?;
+    let response: ServerPacket = decoder
+        .decode()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Failed to parse key exchange."))?;
+    match response.op_code {
+        KEY_EXCHANGE_OC => handshake
+            .finish(Side::Client, &response.data)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Server sent an invalid key.")),
+        MESSAGE_OC => Err(message_error(&response)),
+        op_code => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected op code {} when exchanging keys.", op_code),
+        )),
+    }
+}
+
+/// Writes and clears the encoder, only for the socket while it still blocks.
+fn write_frame(
+    tcp: &mut dyn StreamSocket,
+    encoder: &mut Encoder,
+    cipher: Option<&mut Cipher>,
+) -> std::io::Result<()> {
+    let result = match cipher {
+        Some(cipher) => tcp.write_all(cipher.seal_frame(&encoder.data()[Encoder::LEN_SIZE..])),
+        None => tcp.write_all(encoder.data()),
+    };
+    encoder.clear();
+    result
+}
+
+/// Decrypts the frame in the decoder in place.
+fn open_frame(decoder: &mut Decoder, cipher: Option<&mut Cipher>) -> std::io::Result<()> {
+    if let Some(cipher) = cipher {
+        let size = cipher
+            .open_frame(decoder.data_mut())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Failed to decrypt a frame."))?;
+        decoder.expose(size);
+    }
+    Ok(())
+}
+
+fn check_op_code(op_code: u32) -> std::io::Result<()> {
+    if op_code >= FIRST_RESERVED_OC {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Op code {} is reserved.", op_code),
+        ));
+    }
+    Ok(())
+}
+
+fn decode_data<T: Bitwise + Default>(data: &[u8]) -> Option<T> {
+    let mut t = T::default();
+    t.decode(&mut 0, data)?;
+    Some(t)
+}
+
+/// Error for a message answering a request in place of the response.
+fn message_error(packet: &ServerPacket) -> Error {
+    match decode_data(&packet.data) {
+        Some(ServerMessage::Rejected(rejection)) => {
+            Error::new(ErrorKind::PermissionDenied, rejection)
+        }
+        Some(message) => Error::other(format!("Unexpected message {:?}.", message)),
+        None => Error::new(ErrorKind::InvalidData, "Failed to parse server message."),
     }
 }
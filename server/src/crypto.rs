@@ -0,0 +1,222 @@
+use bitwise::Encoder;
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Size of the tag every encrypted frame and datagram ends with.
+pub const TAG_SIZE: usize = 16;
+
+const INFO: &[u8] = b"server transport v1";
+// nonces start with the transport and the sending side so that no two
+// messages share one
+const TCP: u8 = 0;
+const UDP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn peer(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
+}
+
+/// One side of the x25519 exchange done before the join request. Both keys
+/// are ephemeral and nothing authenticates the server's, so the resulting
+/// cipher only keeps passive eavesdroppers out. Whoever sits on the join
+/// path can answer with its own key and read or change everything.
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+
+    /// Derives the transport key, fails when the peer key is malformed or
+    /// would make the shared secret predictable.
+    pub fn finish(self, side: Side, peer: &[u8]) -> Option<Cipher> {
+        let peer = PublicKey::from(<[u8; 32]>::try_from(peer).ok()?);
+        let shared = self.secret.diffie_hellman(&peer);
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let (client, server) = match side {
+            Side::Client => (self.public, peer),
+            Side::Server => (peer, self.public),
+        };
+        let mut info = INFO.to_vec();
+        info.extend_from_slice(client.as_bytes());
+        info.extend_from_slice(server.as_bytes());
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .expect("key fits the hkdf output");
+
+        Some(Cipher {
+            aead: ChaCha20Poly1305::new(&key.into()),
+            side,
+            sent_frames: 0,
+            received_frames: 0,
+            buffer: vec![],
+        })
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ChaCha20-Poly1305 for one connection. Tcp frames are numbered
+/// implicitly, datagrams use the sequence from their header.
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    side: Side,
+    sent_frames: u64,
+    received_frames: u64,
+    buffer: Vec<u8>,
+}
+
+impl Cipher {
+    /// Encrypts `payload` into a tcp frame, length prefix included.
+    pub fn seal_frame(&mut self, payload: &[u8]) -> &[u8] {
+        let nonce = nonce(TCP, self.side, self.sent_frames);
+        self.sent_frames += 1;
+
+        self.buffer.clear();
+        let size = (payload.len() + TAG_SIZE) as u32;
+        self.buffer.extend_from_slice(&size.to_le_bytes());
+        self.buffer.extend_from_slice(payload);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&nonce, &[], &mut self.buffer[Encoder::LEN_SIZE..])
+            .expect("frames are shorter than the cipher limit");
+        self.buffer.extend_from_slice(&tag);
+        &self.buffer
+    }
+
+    /// Decrypts a received frame without its length prefix in place and
+    /// returns the size of the payload. Frames have to be opened in the
+    /// order they were sent.
+    pub fn open_frame(&mut self, frame: &mut [u8]) -> Option<usize> {
+        let nonce = nonce(TCP, self.side.peer(), self.received_frames);
+        let size = open(&self.aead, &nonce, &[], frame)?;
+        self.received_frames += 1;
+        Some(size)
+    }
+
+    /// Encrypts `bytes` in place and returns the tag to append. `header`
+    /// stays readable but is authenticated too.
+    pub fn seal_datagram(&self, sequence: u64, header: &[u8], bytes: &mut [u8]) -> [u8; TAG_SIZE] {
+        self.aead
+            .encrypt_in_place_detached(&nonce(UDP, self.side, sequence), header, bytes)
+            .expect("datagrams are shorter than the cipher limit")
+            .into()
+    }
+
+    /// Decrypts `bytes`, which end with the tag, in place and returns the
+    /// size of the plaintext.
+    pub fn open_datagram(&self, sequence: u64, header: &[u8], bytes: &mut [u8]) -> Option<usize> {
+        open(
+            &self.aead,
+            &nonce(UDP, self.side.peer(), sequence),
+            header,
+            bytes,
+        )
+    }
+}
+
+fn nonce(transport: u8, from: Side, counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[0] = transport | from as u8;
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+fn open(aead: &ChaCha20Poly1305, nonce: &Nonce, header: &[u8], bytes: &mut [u8]) -> Option<usize> {
+    let size = bytes.len().checked_sub(TAG_SIZE)?;
+    let (bytes, tag) = bytes.split_at_mut(size);
+    aead.decrypt_in_place_detached(nonce, header, bytes, Tag::from_slice(tag))
+        .ok()?;
+    Some(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair() -> (Cipher, Cipher) {
+        let client = Handshake::new();
+        let server = Handshake::new();
+        let client_key = client.public_key();
+        let server_key = server.public_key();
+        (
+            client.finish(Side::Client, &server_key).unwrap(),
+            server.finish(Side::Server, &client_key).unwrap(),
+        )
+    }
+
+    #[test]
+    fn frames() {
+        let (mut client, mut server) = pair();
+        for payload in [&b"join"[..], b"x", b"second"] {
+            let mut frame = client.seal_frame(payload).to_vec();
+            assert_ne!(&frame[Encoder::LEN_SIZE..][..payload.len()], payload);
+            let size = server.open_frame(&mut frame[Encoder::LEN_SIZE..]).unwrap();
+            assert_eq!(&frame[Encoder::LEN_SIZE..][..size], payload);
+        }
+
+        // a frame is bound to its position and direction
+        let mut first = server.seal_frame(b"a").to_vec();
+        let mut second = server.seal_frame(b"b").to_vec();
+        assert!(client
+            .open_frame(&mut second[Encoder::LEN_SIZE..])
+            .is_none());
+        let mut reflected = first.clone();
+        assert!(server
+            .open_frame(&mut reflected[Encoder::LEN_SIZE..])
+            .is_none());
+        assert!(client.open_frame(&mut first[Encoder::LEN_SIZE..]).is_some());
+    }
+
+    #[test]
+    fn datagrams() {
+        let (client, server) = pair();
+        let mut bytes = b"frame".to_vec();
+        let tag = client.seal_datagram(7, b"header", &mut bytes);
+        bytes.extend_from_slice(&tag);
+
+        let mut tampered = bytes.clone();
+        assert!(server.open_datagram(7, b"heade!", &mut tampered).is_none());
+        assert!(server.open_datagram(8, b"header", &mut tampered).is_none());
+        tampered[0] ^= 1;
+        assert!(server.open_datagram(7, b"header", &mut tampered).is_none());
+
+        assert_eq!(server.open_datagram(7, b"header", &mut bytes), Some(5));
+        assert_eq!(&bytes[..5], b"frame");
+    }
+
+    #[test]
+    fn weak_keys() {
+        assert!(Handshake::new().finish(Side::Client, &[0; 32]).is_none());
+        assert!(Handshake::new().finish(Side::Client, &[1; 31]).is_none());
+    }
+}
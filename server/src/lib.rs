@@ -1,3 +1,7 @@
+pub mod auth;
+pub mod channel;
 pub mod client;
+pub mod crypto;
+pub mod link;
 pub mod protocol;
 pub mod server;
@@ -0,0 +1,453 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Tcp side of a connection, implemented by `TcpStream` and by its
+/// conditioned wrapper.
+pub trait StreamSocket: Read + Write + Send {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()>;
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
+}
+
+/// Udp side of a connection. Sockets handed out by a `Network` are always
+/// nonblocking.
+pub trait DatagramSocket: Send {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+impl StreamSocket for TcpStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        UdpSocket::peek(self, buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Wraps sockets the server and client open, so tests can put them
+/// behind a `LinkConditioner`.
+pub trait Network: Send + Sync {
+    fn stream(&self, tcp: TcpStream) -> std::io::Result<Box<dyn StreamSocket>>;
+    fn datagram(&self, udp: UdpSocket) -> std::io::Result<Box<dyn DatagramSocket>>;
+}
+
+/// Plain sockets.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Direct;
+
+impl Network for Direct {
+    fn stream(&self, tcp: TcpStream) -> std::io::Result<Box<dyn StreamSocket>> {
+        Ok(Box::new(tcp))
+    }
+
+    fn datagram(&self, udp: UdpSocket) -> std::io::Result<Box<dyn DatagramSocket>> {
+        udp.set_nonblocking(true)?;
+        Ok(Box::new(udp))
+    }
+}
+
+/// Conditions applied to everything a socket receives. Reordering comes
+/// from jitter, datagrams are handed out by arrival time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Conditions {
+    pub latency: Duration,
+    /// Extra delay, uniformly distributed up to this value.
+    pub jitter: Duration,
+    /// Chance of dropping a datagram, tcp is never dropped.
+    pub loss: f64,
+    /// Chance of delivering a datagram twice.
+    pub duplicate: f64,
+}
+
+/// Network that simulates bad links in process. Every socket gets its own
+/// random generator derived from the seed and the order sockets are
+/// wrapped in, so the same traffic is conditioned the same way every run.
+pub struct LinkConditioner {
+    conditions: Conditions,
+    seed: u64,
+    sockets: AtomicU64,
+}
+
+impl LinkConditioner {
+    pub fn new(conditions: Conditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            seed,
+            sockets: AtomicU64::new(0),
+        }
+    }
+
+    fn rng(&self) -> SplitMix {
+        let socket = self.sockets.fetch_add(1, Ordering::Relaxed);
+        SplitMix(self.seed ^ SplitMix(socket).next())
+    }
+}
+
+impl Network for LinkConditioner {
+    fn stream(&self, tcp: TcpStream) -> std::io::Result<Box<dyn StreamSocket>> {
+        Ok(Box::new(ConditionedStream {
+            inner: tcp,
+            conditions: self.conditions,
+            rng: self.rng(),
+            nonblocking: false,
+            closed: false,
+            queue: VecDeque::new(),
+        }))
+    }
+
+    fn datagram(&self, udp: UdpSocket) -> std::io::Result<Box<dyn DatagramSocket>> {
+        udp.set_nonblocking(true)?;
+        Ok(Box::new(ConditionedDatagram {
+            inner: udp,
+            conditions: self.conditions,
+            rng: self.rng(),
+            arrived: 0,
+            queue: vec![],
+            buffer: vec![0; u16::MAX as usize],
+        }))
+    }
+}
+
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0.0..1.0`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.unit() < probability
+    }
+
+    fn delay(&mut self, conditions: &Conditions) -> Duration {
+        conditions.latency + conditions.jitter.mul_f64(self.unit())
+    }
+}
+
+struct ConditionedStream {
+    inner: TcpStream,
+    conditions: Conditions,
+    rng: SplitMix,
+    nonblocking: bool,
+    closed: bool,
+    // chunks with the time they may be read, always in order
+    queue: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl ConditionedStream {
+    fn arrive(&mut self, bytes: &[u8]) {
+        let mut ready = Instant::now() + self.rng.delay(&self.conditions);
+        // a stream cannot overtake itself
+        if let Some(&(last, _)) = self.queue.back() {
+            ready = ready.max(last);
+        }
+        self.queue.push_back((ready, bytes.to_vec()));
+    }
+
+    fn read_inner(&mut self) -> std::io::Result<()> {
+        let mut chunk = [0; 4096];
+        match self.inner.read(&mut chunk)? {
+            0 => self.closed = true,
+            read => self.arrive(&chunk[..read]),
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let (_, front) = self.queue.front_mut().unwrap();
+        let read = buf.len().min(front.len());
+        buf[..read].copy_from_slice(&front[..read]);
+        front.drain(..read);
+        if front.is_empty() {
+            self.queue.pop_front();
+        }
+        read
+    }
+}
+
+impl Read for ConditionedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.nonblocking {
+            // pull everything now so the delay counts from arrival
+            while !self.closed {
+                match self.read_inner() {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            return match self.queue.front() {
+                Some(&(ready, _)) if ready <= Instant::now() => Ok(self.take(buf)),
+                Some(_) => Err(ErrorKind::WouldBlock.into()),
+                None if self.closed => Ok(0),
+                None => Err(ErrorKind::WouldBlock.into()),
+            };
+        }
+
+        if self.queue.is_empty() {
+            if self.closed {
+                return Ok(0);
+            }
+            self.read_inner()?;
+            if self.closed {
+                return Ok(0);
+            }
+        }
+        let (ready, _) = self.queue[0];
+        thread::sleep(ready.saturating_duration_since(Instant::now()));
+        Ok(self.take(buf))
+    }
+}
+
+impl Write for ConditionedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl StreamSocket for ConditionedStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.nonblocking = nonblocking;
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+struct ConditionedDatagram {
+    inner: UdpSocket,
+    conditions: Conditions,
+    rng: SplitMix,
+    arrived: u64,
+    // (ready, arrival order, data, source), the earliest ready one goes first
+    queue: Vec<(Instant, u64, Vec<u8>, SocketAddr)>,
+    buffer: Vec<u8>,
+}
+
+impl ConditionedDatagram {
+    fn collect(&mut self) -> std::io::Result<()> {
+        loop {
+            let (read, addr) = match self.inner.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if self.rng.chance(self.conditions.loss) {
+                continue;
+            }
+            let copies = 1 + self.rng.chance(self.conditions.duplicate) as usize;
+            for _ in 0..copies {
+                let ready = Instant::now() + self.rng.delay(&self.conditions);
+                self.arrived += 1;
+                self.queue
+                    .push((ready, self.arrived, self.buffer[..read].to_vec(), addr));
+            }
+        }
+    }
+
+    fn ready(&mut self) -> std::io::Result<usize> {
+        self.collect()?;
+        let now = Instant::now();
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(|(_, (ready, ..))| *ready <= now)
+            .min_by_key(|(_, (ready, arrived, ..))| (*ready, *arrived))
+            .map(|(i, _)| i)
+            .ok_or_else(|| Error::from(ErrorKind::WouldBlock))
+    }
+}
+
+impl DatagramSocket for ConditionedDatagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let i = self.ready()?;
+        let (_, _, data, addr) = self.queue.swap_remove(i);
+        let read = buf.len().min(data.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        Ok((read, addr))
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let i = self.ready()?;
+        let data = &self.queue[i].2;
+        let read = buf.len().min(data.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        Ok(read)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn received(seed: u64) -> Vec<u8> {
+        let conditions = Conditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(20),
+            loss: 0.2,
+            duplicate: 0.1,
+        };
+        let network = LinkConditioner::new(conditions, seed);
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut receiver = network
+            .datagram(UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        for i in 0..100u8 {
+            sender.send_to(&[i], addr).unwrap();
+        }
+
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while Instant::now() < deadline {
+            let mut buf = [0];
+            match receiver.recv_from(&mut buf) {
+                Ok((1, from)) => {
+                    assert_eq!(from, sender.local_addr().unwrap());
+                    received.push(buf[0]);
+                }
+                Ok(_) => unreachable!(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn conditioned_datagrams() {
+        let first = received(7);
+        let mut sorted = first.clone();
+        sorted.sort();
+        let unique = sorted.windows(2).filter(|pair| pair[0] != pair[1]).count() + 1;
+        assert!((60..95).contains(&unique), "{} arrived", unique);
+        assert!(first.len() > unique, "nothing was duplicated");
+        assert_ne!(first, sorted, "nothing was reordered");
+
+        // the same seed loses and duplicates the same datagrams
+        let mut second = received(7);
+        second.sort();
+        assert_eq!(sorted, second);
+    }
+
+    #[test]
+    fn delayed_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let conditions = Conditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let network = LinkConditioner::new(conditions, 1);
+        let mut receiver = network.stream(listener.accept().unwrap().0).unwrap();
+
+        let start = Instant::now();
+        for i in 0..10u8 {
+            sender.write_all(&[i; 100]).unwrap();
+        }
+        let mut data = [0; 1000];
+        receiver.read_exact(&mut data).unwrap();
+        assert!(start.elapsed() >= conditions.latency);
+        assert!(data
+            .chunks(100)
+            .enumerate()
+            .all(|(i, chunk)| chunk == [i as u8; 100]));
+
+        receiver.set_nonblocking(true).unwrap();
+        sender.write_all(&[1]).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let mut byte = [0];
+        let would_block = receiver.read(&mut byte).unwrap_err();
+        assert_eq!(would_block.kind(), ErrorKind::WouldBlock);
+        thread::sleep(conditions.latency + conditions.jitter);
+        assert_eq!(receiver.read(&mut byte).unwrap(), 1);
+
+        drop(sender);
+        thread::sleep(conditions.latency + conditions.jitter);
+        assert_eq!(receiver.read(&mut byte).unwrap(), 0);
+    }
+}
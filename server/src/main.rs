@@ -1,7 +1,3 @@
-#![feature(auto_traits)]
-#![feature(negative_impls)]
-#![feature(tcplistener_into_incoming)]
-
 extern crate server;
 
 fn main() {
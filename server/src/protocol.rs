@@ -1,9 +1,67 @@
-use std::{net::{TcpStream, UdpSocket, SocketAddr}, io::Read};
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+};
 
 pub use bitwise::*;
 
+use crate::{
+    auth::{self, Token},
+    channel::{Connection, Frame},
+    crypto::{self, Cipher},
+    link::DatagramSocket,
+};
+
 store::create_access!(Player Session);
 
+/// Op codes from this one up are handled by the server itself and are never
+/// forwarded, players use the ones below.
+pub const FIRST_RESERVED_OC: u32 = u32::MAX - 15;
+pub const JOIN_REQUEST_OC: u32 = u32::MAX;
+/// Everything the server tells a player on its own, `data` holds the
+/// `ServerMessage`.
+pub const MESSAGE_OC: u32 = u32::MAX - 1;
+/// Sent by the owner to remove the first target, `data` holds a `BanScope`
+/// telling what is refused from then on.
+pub const KICK_REQUEST_OC: u32 = u32::MAX - 2;
+/// Sent by the client over udp so the server learns its address, confirmed
+/// over tcp with the same op code.
+pub const UDP_REGISTER_OC: u32 = u32::MAX - 4;
+/// Optional first frame of the client, followed by its x25519 public key as
+/// `Vec<u8>`. The server answers with its own key in `data` and every frame
+/// after that, the join request included, is encrypted.
+pub const KEY_EXCHANGE_OC: u32 = u32::MAX - 5;
+/// Sent instead of a join request, followed by `Vec<SessionFilter>`. The
+/// server answers with the matching `Vec<SessionListing>` in `data` and
+/// closes the connection.
+pub const LIST_SESSIONS_OC: u32 = u32::MAX - 6;
+/// Discovery probe, a datagram holding the op code padded with zeros to
+/// `DISCOVERY_PROBE_SIZE`. Servers answer with a `ServerPacket` holding their
+/// `ServerInfo`, never bigger than the probe.
+pub const DISCOVER_OC: u32 = u32::MAX - 7;
+/// Sent by the owner to hand the session over to the first target.
+pub const TRANSFER_OWNER_OC: u32 = u32::MAX - 9;
+/// Sent by the owner, packets of the targets are no longer forwarded while
+/// the `bool` in `data` is true.
+pub const MUTE_OC: u32 = u32::MAX - 11;
+/// Sent by the owner with the new `JoinPolicy` in `data`.
+pub const JOIN_POLICY_OC: u32 = u32::MAX - 12;
+/// Sent by the owner to get an invite code, which the server sends back as
+/// `ServerMessage::Invite`. The code joins an invite-only session once, in
+/// place of the password.
+pub const INVITE_OC: u32 = u32::MAX - 13;
+
+/// Version of this crate, servers report it when discovered.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Default port discovery probes are sent to.
+pub const DISCOVERY_PORT: u16 = 38400;
+/// Smallest probe that is answered, so answers cannot amplify traffic sent
+/// with a forged source.
+pub const DISCOVERY_PROBE_SIZE: usize = MAX_DATAGRAM;
+/// Multicast group the discovery responder joins, for networks that drop
+/// broadcasts.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 38, 40);
+
 #[derive(Bitwise, Debug)]
 pub enum OPCode {
     None,
@@ -27,52 +85,427 @@ pub struct Packet {
     pub data: Vec<u8>,
 }
 
-#[derive(Bitwise, Debug, Default)]
+impl Packet {
+    /// Decoding appends to vectors, pooled packets have to be cleared first.
+    pub fn clear(&mut self) {
+        self.targets.clear();
+        self.data.clear();
+    }
+}
+
+/// Start of a udp datagram. A `Frame` follows and the datagram ends with a
+/// mac over both, keyed by the token of the player. Messages in the frame
+/// hold a `Packet` when sent by players and a `ServerPacket` when sent by
+/// the server.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq)]
+pub struct DatagramHeader {
+    pub session: Session,
+    pub source: Player,
+    /// Counts datagrams in one direction, for replay protection.
+    pub sequence: u64,
+}
+
+impl DatagramHeader {
+    pub fn encode_signed(&self, encoder: &mut Encoder, frame: &Frame, token: Token) {
+        encoder.encode(self);
+        encoder.encode(frame);
+        let mac = auth::sign(token, &encoder.data[Encoder::LEN_SIZE..]);
+        encoder.data.extend_from_slice(&mac);
+    }
+
+    /// Decodes the frame following the already decoded header, if the mac
+    /// matches.
+    pub fn decode_frame(decoder: &mut Decoder, token: Token) -> Option<Frame> {
+        if !auth::verify(token, &decoder.data()[Encoder::LEN_SIZE..]) {
+            return None;
+        }
+        decoder.decode()
+    }
+
+    /// Encrypted variant of `encode_signed`, the tag of the cipher takes
+    /// the place of the mac.
+    pub fn encode_sealed(&self, encoder: &mut Encoder, frame: &Frame, cipher: &Cipher) {
+        encoder.encode(self);
+        let header_end = encoder.data.len();
+        encoder.encode(frame);
+        let (header, bytes) = encoder.data.split_at_mut(header_end);
+        let tag = cipher.seal_datagram(self.sequence, &header[Encoder::LEN_SIZE..], bytes);
+        encoder.data.extend_from_slice(&tag);
+    }
+
+    /// Decrypts and decodes the frame following the already decoded header.
+    pub fn open_frame(&self, decoder: &mut Decoder, cipher: &Cipher) -> Option<Frame> {
+        let cursor = decoder.cursor();
+        let (header, bytes) = decoder.data_mut().split_at_mut(cursor);
+        cipher.open_datagram(self.sequence, &header[Encoder::LEN_SIZE..], bytes)?;
+        decoder.decode()
+    }
+}
+
+/// Everything the server sends to players has this layout.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
 pub struct ServerPacket {
     pub op_code: u32,
     pub source: Player,
     pub data: Vec<u8>,
 }
 
-#[derive(Bitwise, Debug, Default)]
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq)]
 pub struct JoinInfo {
     pub thread_id: u32,
     pub session: Session,
     pub joined: Player,
+    pub owner: Player,
     pub udp_port: u16,
+    /// Only the player that joined gets its token, others see zero.
+    pub token: Token,
 }
 
 #[derive(Bitwise, Debug, Default)]
 pub struct JoinRequestData {
+    /// Invite code when joining an invite-only session.
     pub password: u128,
     pub session: Session,
     pub thread: u32,
+    /// Only used when creating a session.
+    pub settings: SessionSettings,
+    /// Identity the client keeps across connections, 0 for none. Bans
+    /// record it next to the address and refuse either one.
+    pub client_id: u128,
 }
 
 impl JoinRequestData {
-    pub const NEW_SESSION_ID: Session = Session(u32::MAX);
+    pub const NEW_SESSION_ID: Session = Session(u32::MAX, 0);
 
     pub fn create(password: u128) -> Self {
+        Self::create_with(password, SessionSettings::default())
+    }
+
+    pub fn create_with(password: u128, settings: SessionSettings) -> Self {
         Self {
             password,
             session: Self::NEW_SESSION_ID,
             thread: u32::MAX,
+            settings,
+            client_id: 0,
         }
     }
+
+    pub fn join(password: u128, session: Session, thread: u32) -> Self {
+        Self {
+            password,
+            session,
+            thread,
+            settings: SessionSettings::default(),
+            client_id: 0,
+        }
+    }
+
+    pub fn with_client_id(mut self, client_id: u128) -> Self {
+        self.client_id = client_id;
+        self
+    }
 }
 
-pub fn read_tcp_packet_bytes(tcp: &mut TcpStream, into: &mut Decoder) -> std::io::Result<()> {
-    let mut length = [0; 4];
-    tcp.read(&mut length)?;
-    let length = u32::from_le_bytes(length);
-    tcp.read(into.expose(length as usize))?;
+/// Why the server refused a request.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[default]
+    InvalidRequest,
+    ThreadNotFound,
+    SessionNotFound,
+    NameTooLong,
+    WrongPassword,
+    InviteRequired,
+    SessionFull,
+    Banned,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            Self::InvalidRequest => "Request in invalid format!",
+            Self::ThreadNotFound => "Thread does not exists!",
+            Self::SessionNotFound => "Session does not exists!",
+            Self::NameTooLong => "Session name is too long!",
+            Self::WrongPassword => "Wrong password!",
+            Self::InviteRequired => "Session is invite only!",
+            Self::SessionFull => "Session is full!",
+            Self::Banned => "You are banned from the session!",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Why a player was removed from the session.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KickReason {
+    #[default]
+    ByOwner,
+    Banned,
+    Garbage,
+    Inactivity,
+    /// Reliable messages sent over udp were not acked in time.
+    Unresponsive,
+}
+
+/// Sent with `MESSAGE_OC`.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ServerMessage {
+    /// The request needs the session owner.
+    #[default]
+    NotOwner,
+    /// The request needs a target but has none.
+    NoTarget,
+    /// The target of the request is not in the session.
+    TargetNotFound,
+    /// Data of a reserved op code is in invalid format.
+    InvalidRequest,
+    /// A datagram came from another ip than the tcp connection.
+    AddressMismatch,
+    /// The join or listing request was refused, the connection is closed
+    /// after it.
+    Rejected(Rejection),
+    /// The player was removed, the connection is closed after it.
+    Kicked {
+        reason: KickReason,
+    },
+    /// Sent to everyone in the session, the joined player included, which
+    /// is the only one seeing its token.
+    PlayerJoined(JoinInfo),
+    PlayerLeft(Player),
+    /// The session got a new owner, because the previous one left or handed
+    /// it over.
+    OwnerChanged(Player),
+    /// Answer to `INVITE_OC`.
+    Invite(u128),
+}
+
+/// What a kick refuses from then on. Encoded like a `bool` for the first
+/// two, so older clients still kick or ban by id.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BanScope {
+    /// Nothing, the player may join again.
+    #[default]
+    None,
+    /// The client id of the join request. The client picks it, so only
+    /// players that keep their id stay out, and without one nothing is
+    /// refused.
+    Id,
+    /// The client id and the address, everyone behind the same NAT or LAN
+    /// address included. The owner that banned it still gets in when it
+    /// joins with the client id it had.
+    Ip,
+}
+
+/// Who may join a session, changed by the owner.
+#[derive(Bitwise, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JoinPolicy {
+    /// Anyone, the password is ignored.
+    Open,
+    /// Anyone with the password.
+    #[default]
+    Password,
+    /// Only with an invite code from the owner. Such sessions are not listed.
+    InviteOnly,
+}
+
+/// Chosen by the player creating the session, everything but `private`
+/// shows up in listings.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub struct SessionSettings {
+    pub name: String,
+    /// Zero means no limit.
+    pub max_players: u32,
+    /// Meaning is up to the game.
+    pub game_mode: u32,
+    /// Private sessions are never listed, they can only be joined by id.
+    pub private: bool,
+}
+
+impl SessionSettings {
+    /// Longer names are rejected.
+    pub const MAX_NAME: usize = 64;
+}
+
+/// Public metadata of a session, as listed by the server.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub struct SessionListing {
+    pub session: Session,
+    pub thread_id: u32,
+    pub name: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub password: bool,
+    pub game_mode: u32,
+}
+
+impl SessionListing {
+    pub fn is_full(&self) -> bool {
+        self.max_players != 0 && self.players >= self.max_players
+    }
+}
+
+/// Answer to a discovery probe.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+    /// Tcp port to join on.
+    pub port: u16,
+    /// Public sessions that are not full, as many as fit the datagram.
+    pub sessions: Vec<SessionListing>,
+}
+
+/// A listing has to pass every filter of the query to be returned.
+#[derive(Bitwise, Debug, Default, Clone, PartialEq)]
+pub enum SessionFilter {
+    /// Case insensitive.
+    NameContains(String),
+    GameMode(u32),
+    #[default]
+    NotFull,
+    NoPassword,
+}
+
+impl SessionFilter {
+    pub fn matches(&self, listing: &SessionListing) -> bool {
+        match self {
+            Self::NameContains(name) => listing.name.to_lowercase().contains(&name.to_lowercase()),
+            Self::GameMode(mode) => listing.game_mode == *mode,
+            Self::NotFull => !listing.is_full(),
+            Self::NoPassword => !listing.password,
+        }
+    }
+}
+
+/// Largest tcp frame without its length, anything bigger is refused and
+/// the connection dropped.
+pub const MAX_TCP_FRAME: usize = 64 * 1024;
+
+/// Largest datagram, a full frame with its length, header and either the
+/// mac or the tag.
+pub const MAX_DATAGRAM: usize = Encoder::LEN_SIZE
+    + std::mem::size_of::<DatagramHeader>()
+    + Connection::MAX_FRAME_SIZE
+    + auth::MAC_SIZE
+    + crypto::TAG_SIZE;
+
+/// Unsent bytes a connection may pile up before the peer counts as stalled.
+pub const MAX_TCP_QUEUE: usize = 1024 * 1024;
+
+/// Writes as much of `queue` as the socket takes without blocking, the rest
+/// stays queued for the next call. Fails once more than `MAX_TCP_QUEUE`
+/// bytes are waiting.
+pub fn flush_tcp(tcp: &mut (impl Write + ?Sized), queue: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut written = 0;
+    let result = loop {
+        if written == queue.len() {
+            break Ok(());
+        }
+        match tcp.write(&queue[written..]) {
+            Ok(0) => break Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(sent) => written += sent,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    queue.drain(..written);
+    result?;
+
+    if queue.len() > MAX_TCP_QUEUE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "peer stopped reading",
+        ));
+    }
     Ok(())
 }
 
-pub fn read_udp_packet_bytes(udp: &mut UdpSocket, into: &mut Decoder) -> std::io::Result<SocketAddr> {
+/// Error for a frame bigger than `max`.
+pub fn oversized(size: usize, max: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("frame of {} bytes is bigger than {}", size, max),
+    )
+}
+
+pub fn read_tcp_packet_bytes(
+    tcp: &mut (impl Read + ?Sized),
+    into: &mut Decoder,
+) -> std::io::Result<()> {
     let mut length = [0; 4];
-    udp.peek(&mut length)?;
-    let length = u32::from_le_bytes(length);
-    let (_, addr) = udp.recv_from(into.expose(length as usize + 4))?;
+    tcp.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_TCP_FRAME {
+        return Err(oversized(length, MAX_TCP_FRAME));
+    }
+    tcp.read_exact(into.expose(length))?;
+    Ok(())
+}
+
+/// Fails with `InvalidData` for datagrams bigger than `MAX_DATAGRAM`, they
+/// are dropped and the next one can be read.
+pub fn read_udp_packet_bytes(
+    udp: &mut dyn DatagramSocket,
+    into: &mut Decoder,
+) -> std::io::Result<SocketAddr> {
+    // one byte more tells a datagram of the largest size from a cut one
+    let (received, addr) = udp.recv_from(into.expose(MAX_DATAGRAM + 1))?;
+    if received > MAX_DATAGRAM {
+        return Err(oversized(received, MAX_DATAGRAM));
+    }
+    into.expose(received);
     Ok(addr)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Takes a few bytes per call, like a socket with a full send buffer.
+    struct Trickle {
+        written: Vec<u8>,
+        budget: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.budget == 0 {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let sent = buf.len().min(3).min(self.budget);
+            self.budget -= sent;
+            self.written.extend_from_slice(&buf[..sent]);
+            Ok(sent)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes_stay_queued() {
+        let mut tcp = Trickle {
+            written: vec![],
+            budget: 4,
+        };
+        let mut queue = b"first".to_vec();
+        flush_tcp(&mut tcp, &mut queue).unwrap();
+        assert_eq!((&*tcp.written, &*queue), (&b"firs"[..], &b"t"[..]));
+
+        queue.extend_from_slice(b"second");
+        tcp.budget = 100;
+        flush_tcp(&mut tcp, &mut queue).unwrap();
+        assert_eq!(tcp.written, b"firstsecond");
+        assert!(queue.is_empty());
+
+        tcp.budget = 0;
+        let mut stalled = vec![0; MAX_TCP_QUEUE + 1];
+        assert!(flush_tcp(&mut tcp, &mut stalled).is_err());
+    }
+}
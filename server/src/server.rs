@@ -1,19 +1,31 @@
 use std::{
     cell::RefCell,
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    collections::{HashMap, HashSet, VecDeque},
+    io::Read,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
     sync::{
-        atomic::{AtomicI64, Ordering},
+        atomic::{AtomicBool, AtomicI64, Ordering},
         mpsc::{self, Receiver, Sender},
-        Arc,
+        Arc, Mutex,
     },
     thread,
     time::{Duration, Instant},
 };
 
-use crate::protocol::{JoinInfo, JoinRequestData, Packet, Player, Session};
+use crate::auth::{self, ReplayWindow, Token};
+use crate::channel::{Channel, Connection};
+use crate::crypto::{self, Cipher, Handshake, Side};
+use crate::link::{DatagramSocket, Direct, Network, StreamSocket};
+use crate::protocol::{
+    self, BanScope, DatagramHeader, JoinInfo, JoinPolicy, JoinRequestData, KickReason, Packet,
+    Player, Rejection, ServerInfo, ServerMessage, ServerPacket, Session, SessionFilter,
+    SessionListing, SessionSettings, DISCOVERY_GROUP, DISCOVERY_PROBE_SIZE, DISCOVER_OC,
+    FIRST_RESERVED_OC, INVITE_OC, JOIN_POLICY_OC, JOIN_REQUEST_OC, KEY_EXCHANGE_OC,
+    KICK_REQUEST_OC, LIST_SESSIONS_OC, MAX_TCP_FRAME, MESSAGE_OC, MUTE_OC, TRANSFER_OWNER_OC,
+    UDP_REGISTER_OC,
+};
 use bitwise::*;
-use store::PoolStore;
+use store::{ConcurrentPoolStore, Invalid, PoolStore};
 
 macro_rules! log {
     ($template:literal, $($arg:expr),*) => {
@@ -38,31 +50,130 @@ macro_rules! log {
 }
 
 pub struct Server {
+    address: IpAddr,
     port: u16,
+    thread_count: usize,
+    fps: usize,
+    // spawned by `bind`
     threads: Vec<ThreadHandle>,
+    // thread of every open session, threads add and remove their own
+    sessions: Arc<ConcurrentPoolStore<Session, u32>>,
+    network: Arc<dyn Network>,
+    // name and port of the discovery responder
+    discovery: Option<(String, u16)>,
+    // stops the responder once set
+    responder: Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>,
 }
 
 impl Server {
     pub fn new(thread_count: usize, fps: usize, port: u16) -> Self {
-        let mut threads = Vec::with_capacity(thread_count as usize);
-        for i in 0..thread_count {
-            let (sender, receiver) = mpsc::channel();
-            let resources = Arc::new(AtomicI64::new(0));
-            let mut state = ThreadState::new(i, port, resources.clone());
-            let handle = thread::spawn(move || state.run(fps, receiver));
-            let handle = ThreadHandle::new(sender, resources, handle);
-            threads.push(handle);
+        Self::with_network(thread_count, fps, port, Arc::new(Direct))
+    }
+
+    /// Every socket the server opens or accepts goes through `network`, but
+    /// for the blocking one answering discovery probes.
+    pub fn with_network(
+        thread_count: usize,
+        fps: usize,
+        port: u16,
+        network: Arc<dyn Network>,
+    ) -> Self {
+        Server {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            thread_count,
+            fps,
+            threads: Vec::with_capacity(thread_count),
+            sessions: Arc::new(ConcurrentPoolStore::new()),
+            network,
+            discovery: None,
+            responder: None,
         }
+    }
 
-        Server { port, threads }
+    /// Address the listener and the udp sockets of the threads bind to,
+    /// localhost by default. Players found by discovery need one they can
+    /// reach, like `0.0.0.0`.
+    pub fn with_address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Answers discovery probes on `port` with `name`, see
+    /// `protocol::DISCOVER_OC`.
+    pub fn with_discovery(mut self, name: &str, port: u16) -> Self {
+        self.discovery = Some((name.to_string(), port));
+        self
+    }
+
+    /// Port of the listener, the bound one after `bind`.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Port of the discovery responder, the bound one after `bind`.
+    pub fn discovery_port(&self) -> Option<u16> {
+        self.discovery.as_ref().map(|(_, port)| *port)
     }
 
     pub fn run(&mut self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(("127.0.0.1", self.port))?;
-        let mut decoder = Decoder::new();
+        let listener = self.bind()?;
+        self.serve(listener)
+    }
+
+    /// Binds the listener and starts the threads and the discovery
+    /// responder, port 0 picks a free port that `port` and `discovery_port`
+    /// report afterwards.
+    pub fn bind(&mut self) -> std::io::Result<TcpListener> {
+        let listener = TcpListener::bind((self.address, self.port))?;
+        for i in 0..self.thread_count {
+            // with port 0 every thread picks its own
+            let port = if self.port == 0 {
+                0
+            } else {
+                self.port + i as u16
+            };
+            let udp = UdpSocket::bind((self.address, port))?;
+            let (sender, receiver) = mpsc::channel();
+            let resources = Arc::new(AtomicI64::new(0));
+            let listings = Arc::new(Mutex::new(vec![]));
+            let mut state = ThreadState::new(
+                i,
+                udp.local_addr()?.port(),
+                resources.clone(),
+                listings.clone(),
+                self.sessions.clone(),
+            );
+            let udp = self.network.datagram(udp)?;
+            let fps = self.fps;
+            let handle = thread::spawn(move || state.run(fps, receiver, udp));
+            let handle = ThreadHandle::new(sender, resources, listings, handle);
+            self.threads.push(handle);
+        }
+        self.port = listener.local_addr()?.port();
+
+        if let Some((name, port)) = &mut self.discovery {
+            let listings = self
+                .threads
+                .iter()
+                .map(|thread| thread.listings.clone())
+                .collect();
+            let mut responder = DiscoveryResponder::bind(*port, name, self.port, listings)?;
+            *port = responder.udp.local_addr()?.port();
+            println!("Answering discovery probes on port {}!", port);
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+            let handle = thread::spawn(move || responder.run(&stopped));
+            self.responder = Some((stop, handle));
+        }
 
         println!("Starting to listen udp at port {}!", self.port);
+        Ok(listener)
+    }
 
+    /// Accepts connections from a listener returned by `bind`.
+    pub fn serve(&mut self, listener: TcpListener) -> std::io::Result<()> {
+        let mut decoder = Decoder::new();
         for connection in listener.incoming() {
             match connection {
                 Ok(conn) => self.handle_connection(&mut decoder, conn),
@@ -76,14 +187,28 @@ impl Server {
     }
 
     pub fn handle_connection(&mut self, decoder: &mut Decoder, conn: TcpStream) {
+        let conn = match self.network.stream(conn) {
+            Ok(conn) => conn,
+            Err(e) => {
+                log!("Could not set up connection: {}", e);
+                return;
+            }
+        };
         let mut player = PlayerEnt::new(conn);
 
         player.start_join_timeout();
 
-        let request_data = match player.read_join_request(decoder) {
-            Some(data) => data,
+        let request_data = match player.read_request(decoder) {
+            Some(Request::Join(data)) => {
+                player.client_id = data.client_id;
+                data
+            }
+            Some(Request::List(filters)) => {
+                self.send_listings(&mut player, &filters);
+                return;
+            }
             None => {
-                log!(player.error("Join request in invalid format!"));
+                log!(player.reject(Rejection::InvalidRequest));
                 return;
             }
         };
@@ -101,16 +226,55 @@ impl Server {
             }
         }
 
+        if best >= self.threads.len() {
+            log!(player.reject(Rejection::ThreadNotFound));
+            return;
+        }
+        if request_data.session != JoinRequestData::NEW_SESSION_ID {
+            match self.sessions.get(request_data.session) {
+                Some(thread) if thread as usize == best => {}
+                // the thread still checks, the session may close meanwhile
+                _ => {
+                    log!(player.reject(Rejection::SessionNotFound));
+                    return;
+                }
+            }
+        }
+
         log!("sending connection to thread {}", best);
         self.threads[best]
             .new_connections
             .send(JoinRequest::new(player, request_data))
             .unwrap();
     }
+
+    fn send_listings(&self, player: &mut PlayerEnt, filters: &[SessionFilter]) {
+        let mut listings = vec![];
+        for thread in &self.threads {
+            let published = thread.listings.lock().unwrap();
+            listings.extend(
+                published
+                    .iter()
+                    .filter(|listing| filters.iter().all(|filter| filter.matches(listing)))
+                    .cloned(),
+            );
+        }
+
+        let mut data = vec![];
+        listings.encode(&mut data);
+        let mut encoder = Encoder::new();
+        encoder.encode(&ServerPacket {
+            op_code: LIST_SESSIONS_OC,
+            source: Player::invalid(),
+            data,
+        });
+        log!(player.send(&mut encoder));
+    }
 }
 
 pub struct ThreadHandle {
     resources: Arc<AtomicI64>,
+    listings: Arc<Mutex<Vec<SessionListing>>>,
     new_connections: Sender<JoinRequest>,
     _handle: thread::JoinHandle<()>,
 }
@@ -119,10 +283,12 @@ impl ThreadHandle {
     pub fn new(
         new_connections: Sender<JoinRequest>,
         resources: Arc<AtomicI64>,
+        listings: Arc<Mutex<Vec<SessionListing>>>,
         handle: thread::JoinHandle<()>,
     ) -> Self {
         ThreadHandle {
             resources,
+            listings,
             new_connections,
             _handle: handle,
         }
@@ -133,20 +299,40 @@ pub struct ThreadState {
     id: u32,
     port: u16,
     resources: Arc<AtomicI64>,
-    sessions: PoolStore<Session, SessionEnt>,
+    sessions: HashMap<Session, SessionEnt>,
+    // shared with the listener, which checks join requests against it
+    directory: Arc<ConcurrentPoolStore<Session, u32>>,
+    // public sessions of this thread, read by the listener for listings
+    listings: Arc<Mutex<Vec<SessionListing>>>,
+    // listings are republished at the end of a frame when set
+    sessions_changed: bool,
 }
 
 impl ThreadState {
-    pub fn new(id: usize, port: u16, resources: Arc<AtomicI64>) -> Self {
+    pub fn new(
+        id: usize,
+        port: u16,
+        resources: Arc<AtomicI64>,
+        listings: Arc<Mutex<Vec<SessionListing>>>,
+        directory: Arc<ConcurrentPoolStore<Session, u32>>,
+    ) -> Self {
         Self {
             id: id as u32,
-            port: port + id as u16,
+            port,
             resources,
-            sessions: PoolStore::new(),
+            sessions: HashMap::new(),
+            directory,
+            listings,
+            sessions_changed: false,
         }
     }
 
-    pub fn run(&mut self, fps: usize, mut new_connections: Receiver<JoinRequest>) {
+    pub fn run(
+        &mut self,
+        fps: usize,
+        mut new_connections: Receiver<JoinRequest>,
+        mut udp: Box<dyn DatagramSocket>,
+    ) {
         let mut limiter = FrameLimiter::new();
         let mut decoder = Decoder::new();
         let mut encoder = Encoder::new();
@@ -157,16 +343,12 @@ impl ThreadState {
         limiter.set_fps(fps);
 
         println!("Starting to listen udp on port {}!", self.port);
-        let mut udp = UdpSocket::bind(format!("127.0.0.1:{}", self.port))
-            .expect("Could not bind UDP socket!");
-        udp.set_nonblocking(true)
-            .expect("Could not set nonblocking!");
 
         loop {
             self.collect_new_connections(&mut encoder, &mut new_connections);
 
             match self.collect_udp_packets(
-                &mut udp,
+                &mut *udp,
                 &mut decoder,
                 &mut encoder,
                 &mut kick_queue,
@@ -179,7 +361,7 @@ impl ThreadState {
                 }
             };
 
-            for (session_id, session) in self.sessions.iter_mut() {
+            for (&session_id, session) in self.sessions.iter_mut() {
                 for (id, player) in session.players.iter_mut() {
                     if player
                         .collect_tcp_packages(
@@ -196,24 +378,40 @@ impl ThreadState {
                 }
 
                 for package in &packages {
-                    session.send_package(&mut encoder, &package, &mut kick_queue, &mut udp);
+                    // udp packets sent over tcp were never reliable
+                    let channel = (!package.tcp).then_some(Channel::Unreliable);
+                    session.send_package(&mut encoder, package, channel, &mut kick_queue);
                 }
                 package_pool.append(&mut packages);
 
-                for kick in kick_queue.drain(..) {
-                    // there can be duplicates
-                    if session.players.is_valid(kick) {
-                        session.players.remove(kick);
+                for (id, player) in session.players.iter_mut() {
+                    if let Err(e) = player.flush_tcp() {
+                        log!("failed to send to player {}: {}", id.0, e);
+                        kick_queue.push(id);
                     }
                 }
 
+                let now = Instant::now();
+                session.kick_unresponsive(now, &mut kick_queue);
+                self.sessions_changed |= session.remove_players(&mut encoder, &mut kick_queue);
+                self.sessions_changed |= std::mem::take(&mut session.listing_changed);
+
+                for player in session.players.values_mut() {
+                    log!(player.flush_udp(session_id, &*udp, &mut encoder, now));
+                }
+
                 if session.players.count() == 0 {
                     close_queue.push(session_id);
                 }
             }
 
             for id in close_queue.drain(..) {
-                self.sessions.remove(id);
+                self.sessions.remove(&id);
+                self.directory.try_remove(id);
+            }
+
+            if std::mem::take(&mut self.sessions_changed) {
+                self.publish_listings();
             }
 
             self.resources.store(limiter.update(), Ordering::Relaxed);
@@ -227,17 +425,19 @@ impl ThreadState {
     ) {
         for JoinRequest { mut player, data } in new_connections.try_iter() {
             log!("Connection arrived!",);
+            self.sessions_changed = true;
             if data.session == JoinRequestData::NEW_SESSION_ID {
-                self.create_session(encoder, data.password, player);
+                self.create_session(encoder, data.password, data.settings, player);
                 continue;
             }
 
-            if !self.sessions.is_valid(data.session) {
-                log!(player.error("Session does not exists!"));
-            }
+            let Some(session) = self.sessions.get_mut(&data.session) else {
+                log!(player.reject(Rejection::SessionNotFound));
+                continue;
+            };
 
             log!("Player is joining session {}", data.session.0);
-            self.sessions[data.session].accept(
+            session.accept(
                 encoder,
                 self.id,
                 data.session,
@@ -248,91 +448,268 @@ impl ThreadState {
         }
     }
 
-    pub fn create_session(&mut self, encoder: &mut Encoder, password: u128, player: PlayerEnt) {
-        let session = SessionEnt::new(password, player);
+    pub fn create_session(
+        &mut self,
+        encoder: &mut Encoder,
+        password: u128,
+        settings: SessionSettings,
+        mut player: PlayerEnt,
+    ) {
+        if settings.name.len() > SessionSettings::MAX_NAME {
+            log!(player.reject(Rejection::NameTooLong));
+            return;
+        }
+
+        let session = SessionEnt::new(password, settings, player);
         let joined = session.owner();
-        let session = self.sessions.push(session);
-        log!("Session created with id {}", session.0);
-        encoder.encode(&JoinInfo {
-            session,
+        let id = self.directory.push(self.id);
+        log!("Session created with id {}", id.0);
+        let info = JoinInfo {
+            session: id,
             joined,
+            owner: joined,
             thread_id: self.id,
             udp_port: self.port,
-        });
-        log!(self.sessions[session].send_join_info(joined, encoder));
-        encoder.clear();
+            token: 0,
+        };
+        self.sessions
+            .entry(id)
+            .or_insert(session)
+            .announce(encoder, info);
     }
 
     pub fn collect_udp_packets(
         &mut self,
-        udp: &mut UdpSocket,
+        udp: &mut dyn DatagramSocket,
         decoder: &mut Decoder,
         encoder: &mut Encoder,
         kick_queue: &mut Vec<Player>,
         package_pool: &mut Vec<Packet>,
     ) -> std::io::Result<()> {
-        let mut size = [0u8; 4];
+        let mut packages = vec![];
         loop {
-            udp.peek(&mut size)?;
-            let size = u32::from_le_bytes(size);
-            let (_, addr) = udp.recv_from(decoder.expose(size as usize + Encoder::LEN_SIZE))?;
+            let addr = match protocol::read_udp_packet_bytes(udp, decoder) {
+                Ok(addr) => addr,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    log!("Dropping datagram: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
             decoder.decode::<u32>();
-            let mut package = package_pool.pop().unwrap_or_default();
-            if decoder.decode_into(&mut package).is_none() {
-                package_pool.push(package);
+            let Some(header) = decoder.decode::<DatagramHeader>() else {
                 continue;
-            }
+            };
 
-            if !self.sessions.is_valid(package.session) {
-                log!("Invalid session id {}!", package.session.0);
-                package_pool.push(package);
+            let Some(session) = self.sessions.get_mut(&header.session) else {
+                log!("Invalid session id {}!", header.session.0);
                 continue;
-            }
+            };
 
-            let session = &mut self.sessions[package.session];
-            if !session.players.is_valid(package.source) {
+            let Some(player) = session.players.get_mut(header.source) else {
                 log!(
                     "Player {} is not in session {}!",
-                    package.source.0,
-                    package.session.0
+                    header.source.0,
+                    header.session.0
                 );
-                package_pool.push(package);
+                continue;
+            };
+            // nothing about the player changes before the datagram is
+            // authenticated, so a forged one cannot steal its address
+            let frame = match &player.cipher {
+                Some(cipher) => header.open_frame(decoder, cipher),
+                None => DatagramHeader::decode_frame(decoder, player.token),
+            };
+            let Some(frame) = frame else {
+                log!("Dropping unauthenticated datagram from {}", addr);
+                continue;
+            };
+            // replays are dropped silently, the player is only told about
+            // fresh datagrams from another address
+            if !player.replay.is_fresh(header.sequence) {
+                log!("Dropping replayed datagram from {}", addr);
                 continue;
             }
-
-            let player = &mut session.players[package.source];
-            if !player.set_udp_addr(Some(addr)) {
-                log!(player.error("Udp and tcp ip does not match!"));
-                package_pool.push(package);
+            if !player.set_udp_addr(addr) {
+                log!(player.message(&ServerMessage::AddressMismatch));
                 continue;
             }
+            // a datagram dropped for another reason keeps its sequence
+            player.replay.accept(header.sequence);
+
+            player.connection.receive(frame);
+            while let Some(message) = player.connection.recv() {
+                let mut package = package_pool.pop().unwrap_or_default();
+                package.clear();
+                // forwarding must not make the frame too big for the targets
+                if message.data.len() > Connection::MAX_FRAME_DATA
+                    || package.decode(&mut 0, &message.data).is_none()
+                    || package.session != header.session
+                    || package.source != header.source
+                {
+                    log!("invalid packet: {:?}", package);
+                    package_pool.push(package);
+                    continue;
+                }
 
-            session.send_package(encoder, &package, kick_queue, udp);
-            for kick in kick_queue.drain(..) {
-                // no duplicates this time since we send
-                // just one packet
-                session.players.remove(kick);
+                if package.op_code == UDP_REGISTER_OC {
+                    encoder.encode(&ServerPacket {
+                        op_code: UDP_REGISTER_OC,
+                        source: package.source,
+                        data: vec![],
+                    });
+                    log!(player.send(encoder));
+                    encoder.clear();
+                    package_pool.push(package);
+                    continue;
+                }
+
+                packages.push((package, message.channel));
             }
 
-            package_pool.push(package);
+            for (package, channel) in packages.drain(..) {
+                session.send_package(encoder, &package, Some(channel), kick_queue);
+                package_pool.push(package);
+            }
+            session.kick_unresponsive(Instant::now(), kick_queue);
+            self.sessions_changed |= session.remove_players(encoder, kick_queue);
+            self.sessions_changed |= std::mem::take(&mut session.listing_changed);
         }
     }
+
+    fn publish_listings(&self) {
+        let listings = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_listed())
+            .map(|(&id, session)| session.listing(id, self.id))
+            .collect();
+        *self.listings.lock().unwrap() = listings;
+    }
 }
 
 pub struct SessionEnt {
     players: PoolStore<Player, PlayerEnt>,
     password: u128,
     owner: Player,
+    settings: SessionSettings,
+    policy: JoinPolicy,
+    // tokens are issued per connection, bans go by client id and address,
+    // an address ban lets in the client id of the owner that issued it
+    banned_ids: HashSet<u128>,
+    banned_ips: HashMap<IpAddr, u128>,
+    muted: Vec<Player>,
+    // oldest first, capped at `MAX_INVITES`
+    invites: VecDeque<u128>,
+    // set when something only the listing shows has changed
+    listing_changed: bool,
 }
 
 impl SessionEnt {
-    pub fn new(password: u128, owner: PlayerEnt) -> Self {
+    pub const MAX_INVITES: usize = 16;
+
+    pub fn new(password: u128, settings: SessionSettings, owner: PlayerEnt) -> Self {
         let mut players = PoolStore::new();
         let owner = players.push(owner);
         Self {
             players,
             password,
             owner,
+            settings,
+            policy: JoinPolicy::default(),
+            banned_ids: HashSet::new(),
+            banned_ips: HashMap::new(),
+            muted: vec![],
+            invites: VecDeque::new(),
+            listing_changed: false,
+        }
+    }
+
+    pub fn is_listed(&self) -> bool {
+        !self.settings.private && self.policy != JoinPolicy::InviteOnly
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.settings.max_players != 0 && self.players.count() >= self.settings.max_players as usize
+    }
+
+    pub fn listing(&self, session: Session, thread_id: u32) -> SessionListing {
+        SessionListing {
+            session,
+            thread_id,
+            name: self.settings.name.clone(),
+            players: self.players.count() as u32,
+            max_players: self.settings.max_players,
+            password: self.policy == JoinPolicy::Password && self.password != 0,
+            game_mode: self.settings.game_mode,
+        }
+    }
+
+    /// Removes the queued players, returns whether anyone was still there.
+    /// The player connected for the longest time takes over when the owner
+    /// is removed.
+    pub fn remove_players(&mut self, encoder: &mut Encoder, kick_queue: &mut Vec<Player>) -> bool {
+        let mut removed = false;
+        for kick in kick_queue.drain(..) {
+            // there can be duplicates
+            let Some(mut player) = self.players.try_remove(kick) else {
+                continue;
+            };
+            // last chance for what is still queued, like the kick reason
+            log!(player.flush_tcp());
+            removed = true;
+            self.muted.retain(|&muted| muted != kick);
+            self.broadcast(encoder, &ServerMessage::PlayerLeft(kick));
+        }
+
+        if !self.players.contains(self.owner) {
+            let successor = self
+                .players
+                .iter()
+                .min_by_key(|(_, player)| player.connected_at)
+                .map(|(id, _)| id);
+            if let Some(successor) = successor {
+                log!("Player {} is the new owner", successor.0);
+                self.set_owner(encoder, successor);
+            }
+        }
+        removed
+    }
+
+    /// Hands the session over and tells everyone.
+    pub fn set_owner(&mut self, encoder: &mut Encoder, owner: Player) {
+        self.owner = owner;
+        self.broadcast(encoder, &ServerMessage::OwnerChanged(owner));
+    }
+
+    fn broadcast(&mut self, encoder: &mut Encoder, message: &ServerMessage) {
+        encode_message(encoder, message);
+        for player in self.players.values_mut() {
+            log!(player.send(encoder));
+        }
+        encoder.clear();
+    }
+
+    /// Tells `by` off when it is not the owner.
+    fn check_owner(&mut self, by: Player) -> bool {
+        if by == self.owner {
+            return true;
+        }
+        if let Some(player) = self.players.get_mut(by) {
+            log!(player.message(&ServerMessage::NotOwner));
+        }
+        false
+    }
+
+    pub fn transfer_owner(&mut self, encoder: &mut Encoder, by: Player, target: Player) {
+        if !self.check_owner(by) {
+            return;
+        }
+
+        if self.players.contains(target) {
+            self.set_owner(encoder, target);
+        } else if let Some(player) = self.players.get_mut(by) {
+            log!(player.message(&ServerMessage::TargetNotFound));
         }
     }
 
@@ -345,62 +722,210 @@ impl SessionEnt {
         password: u128,
         mut player: PlayerEnt,
     ) {
-        if self.password != password {
-            log!(player.error("Wrong password!"));
+        let rejection = if self.is_banned(&player) {
+            Some(Rejection::Banned)
+        } else if self.is_full() {
+            Some(Rejection::SessionFull)
+        } else {
+            match self.policy {
+                JoinPolicy::Open => None,
+                JoinPolicy::Password => {
+                    (self.password != password).then_some(Rejection::WrongPassword)
+                }
+                // codes are checked last so that a refused player keeps it
+                JoinPolicy::InviteOnly => {
+                    match self.invites.iter().position(|&code| code == password) {
+                        Some(index) => {
+                            self.invites.remove(index);
+                            None
+                        }
+                        None => Some(Rejection::InviteRequired),
+                    }
+                }
+            }
+        };
+        if let Some(rejection) = rejection {
+            log!(player.reject(rejection));
             return;
         }
 
         let joined = self.players.push(player);
-        encoder.encode(&JoinInfo {
+        let info = JoinInfo {
             thread_id,
             session,
             joined,
+            owner: self.owner,
             udp_port,
-        });
-        if let Err(e) = self.send_join_info(joined, encoder) {
-            log!("failed to send join info: {}", e);
-            self.players.remove(joined);
+            token: 0,
         };
         log!("Session joined with id {}", joined.0);
+        self.announce(encoder, info);
+    }
+
+    /// Queues players that stopped acking for removal.
+    pub fn kick_unresponsive(&mut self, now: Instant, kick_queue: &mut Vec<Player>) {
+        for (id, player) in self.players.iter_mut() {
+            if player.is_unresponsive(now) {
+                log!(player.kicked(KickReason::Unresponsive));
+                kick_queue.push(id);
+            }
+        }
+    }
+
+    /// Either a banned address or a banned client id refuses the player,
+    /// see `BanScope`.
+    fn is_banned(&self, player: &PlayerEnt) -> bool {
+        let id = player.client_id;
+        let banned_id = id != 0 && self.banned_ids.contains(&id);
+        let banned_ip = player
+            .ip()
+            .and_then(|ip| self.banned_ips.get(&ip))
+            .is_some_and(|&owner| id == 0 || id != owner);
+        banned_id || banned_ip
     }
 
-    pub fn kick(&mut self, by: Player, target: Player) {
-        if by != self.owner {
-            log!(self.players[by].error("Only owner can kick!"));
+    pub fn kick(
+        &mut self,
+        by: Player,
+        target: Player,
+        scope: BanScope,
+        kick_queue: &mut Vec<Player>,
+    ) {
+        if !self.check_owner(by) {
             return;
         }
 
-        log!(self.players.remove(target).error("You have been kicked!"));
+        let owner_id = self.players[by].client_id;
+        // the target may have left since the request was queued
+        if let Some(player) = self.players.get_mut(target) {
+            if scope != BanScope::None && player.client_id != 0 {
+                self.banned_ids.insert(player.client_id);
+            }
+            if scope == BanScope::Ip {
+                if let Some(ip) = player.ip() {
+                    log!("Banning {}", ip);
+                    self.banned_ips.insert(ip, owner_id);
+                }
+            }
+            let reason = match scope {
+                BanScope::None => KickReason::ByOwner,
+                _ => KickReason::Banned,
+            };
+            log!(player.message(&ServerMessage::Kicked { reason }));
+            kick_queue.push(target);
+        }
     }
 
-    pub fn send_join_info(&mut self, joined: Player, encoder: &mut Encoder) -> std::io::Result<()> {
-        self.players[joined].stop_blocking();
-        for player in self.players.values_mut() {
-            player.send(encoder, &None)?;
+    pub fn mute(&mut self, by: Player, targets: &[Player], muted: bool) {
+        if !self.check_owner(by) {
+            return;
+        }
+
+        for &target in targets {
+            if !muted {
+                self.muted.retain(|&muted| muted != target);
+            } else if self.players.contains(target) && !self.muted.contains(&target) {
+                self.muted.push(target);
+            }
         }
-        Ok(())
     }
 
+    pub fn set_policy(&mut self, by: Player, policy: JoinPolicy) {
+        if !self.check_owner(by) {
+            return;
+        }
+
+        self.listing_changed |= self.policy != policy;
+        self.policy = policy;
+    }
+
+    /// Sends a fresh invite code to the owner. Past `MAX_INVITES` unused
+    /// codes the oldest one stops working.
+    pub fn invite(&mut self, encoder: &mut Encoder, by: Player) {
+        if !self.check_owner(by) {
+            return;
+        }
+
+        let code = auth::generate_token();
+        if self.invites.len() == Self::MAX_INVITES {
+            self.invites.pop_front();
+        }
+        self.invites.push_back(code);
+        encode_message(encoder, &ServerMessage::Invite(code));
+        log!(self.players[by].send(encoder));
+        encoder.clear();
+    }
+
+    /// Tells everyone about the joined player, only the player itself
+    /// learns its token. Players it does not reach leave the session, the
+    /// joined one included.
+    pub fn announce(&mut self, encoder: &mut Encoder, mut info: JoinInfo) {
+        self.players[info.joined].stop_blocking();
+        let mut unreachable = vec![];
+        for (id, player) in self.players.iter_mut() {
+            info.token = if id == info.joined { player.token } else { 0 };
+            encode_message(encoder, &ServerMessage::PlayerJoined(info));
+            if let Err(e) = player.send(encoder) {
+                log!(
+                    "failed to announce player {} to {}: {}",
+                    info.joined.0,
+                    id.0,
+                    e
+                );
+                unreachable.push(id);
+            }
+            encoder.clear();
+        }
+        self.remove_players(encoder, &mut unreachable);
+    }
+
+    /// Forwards over udp through `channel`, or over tcp when there is none.
     fn send_package(
         &mut self,
         encoder: &mut Encoder,
         data: &Packet,
+        channel: Option<Channel>,
         kick_queue: &mut Vec<Player>,
-        udp: &mut UdpSocket,
     ) {
-        match data.op_code {
-            KICK_REQUEST_OC => {
-                if data.targets.is_empty() {
-                    log!(self.players[data.source].error("No target specified!"));
-                } else {
-                    self.kick(data.source, data.targets[0]);
+        if data.op_code >= FIRST_RESERVED_OC {
+            match data.op_code {
+                KICK_REQUEST_OC | TRANSFER_OWNER_OC | MUTE_OC if data.targets.is_empty() => {
+                    if let Some(player) = self.players.get_mut(data.source) {
+                        log!(player.message(&ServerMessage::NoTarget));
+                    }
+                }
+                KICK_REQUEST_OC => {
+                    // older clients send no scope
+                    let scope = decode_data(&data.data).unwrap_or_default();
+                    self.kick(data.source, data.targets[0], scope, kick_queue);
+                }
+                TRANSFER_OWNER_OC => self.transfer_owner(encoder, data.source, data.targets[0]),
+                MUTE_OC => {
+                    let muted = decode_data(&data.data).unwrap_or(true);
+                    self.mute(data.source, &data.targets, muted);
                 }
+                JOIN_POLICY_OC => match decode_data(&data.data) {
+                    Some(policy) => self.set_policy(data.source, policy),
+                    None => {
+                        if let Some(player) = self.players.get_mut(data.source) {
+                            log!(player.message(&ServerMessage::InvalidRequest));
+                        }
+                    }
+                },
+                INVITE_OC => self.invite(encoder, data.source),
+                op_code => log!("ignoring reserved op code {}", op_code),
             }
-            _ => (),
+            return;
+        }
+
+        if self.muted.contains(&data.source) {
+            return;
         }
 
-        encoder.encode(data);
-        let udp = if data.tcp { None } else { Some(udp) };
+        // same layout as ServerPacket, without copying the data
+        encoder.encode(&data.op_code);
+        encoder.encode(&data.source);
+        encoder.encode(&data.data);
 
         if data.targets.is_empty() {
             for (id, player) in self.players.iter_mut() {
@@ -408,19 +933,21 @@ impl SessionEnt {
                     continue;
                 }
 
-                if player.send_packet(encoder, &udp).is_none() {
+                if player.send_packet(encoder, channel).is_none() {
                     kick_queue.push(id);
                 }
             }
         } else {
             for &target in &data.targets {
-                if self.players.is_valid(target) {
-                    if self.players[target].send_packet(encoder, &udp).is_none() {
-                        kick_queue.push(target);
-                    }
+                let Some(player) = self.players.get_mut(target) else {
+                    continue;
+                };
+                if player.send_packet(encoder, channel).is_none() {
+                    kick_queue.push(target);
                 }
             }
         }
+        encoder.clear();
     }
 
     pub fn owner(&self) -> Player {
@@ -428,6 +955,22 @@ impl SessionEnt {
     }
 }
 
+fn decode_data<T: Bitwise + Default>(data: &[u8]) -> Option<T> {
+    let mut value = T::default();
+    value.decode(&mut 0, data)?;
+    Some(value)
+}
+
+fn encode_message(encoder: &mut Encoder, message: &ServerMessage) {
+    let mut data = vec![];
+    message.encode(&mut data);
+    encoder.encode(&ServerPacket {
+        op_code: MESSAGE_OC,
+        source: Player::invalid(),
+        data,
+    });
+}
+
 pub struct FrameLimiter {
     fps: u32,
     time: Instant,
@@ -458,6 +1001,116 @@ impl FrameLimiter {
     }
 }
 
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.responder.take() {
+            stop.store(true, Ordering::Relaxed);
+            handle.join().ok();
+        }
+    }
+}
+
+struct DiscoveryResponder {
+    udp: UdpSocket,
+    // sessions are filled in for every answer
+    info: ServerInfo,
+    listings: Vec<Arc<Mutex<Vec<SessionListing>>>>,
+}
+
+impl DiscoveryResponder {
+    /// How long a receive waits before the responder checks whether to stop,
+    /// also the pause after an error.
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn bind(
+        port: u16,
+        name: &str,
+        tcp_port: u16,
+        listings: Vec<Arc<Mutex<Vec<SessionListing>>>>,
+    ) -> std::io::Result<Self> {
+        // broadcasts only reach sockets bound to every interface
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        log!(udp.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED));
+        udp.set_read_timeout(Some(Self::TIMEOUT))?;
+        Ok(Self {
+            udp,
+            info: ServerInfo {
+                name: name.to_string(),
+                version: protocol::VERSION.to_string(),
+                port: tcp_port,
+                sessions: vec![],
+            },
+            listings,
+        })
+    }
+
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut decoder = Decoder::new();
+        let mut encoder = Encoder::new();
+        while !stop.load(Ordering::Relaxed) {
+            let addr = match protocol::read_udp_packet_bytes(&mut self.udp, &mut decoder) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    use std::io::ErrorKind::*;
+                    if !matches!(e.kind(), WouldBlock | TimedOut | InvalidData) {
+                        log!("Error when receiving discovery probes: {}", e);
+                        thread::sleep(Self::TIMEOUT);
+                    }
+                    continue;
+                }
+            };
+
+            let probe = decoder.len();
+            decoder.decode::<u32>();
+            if probe < DISCOVERY_PROBE_SIZE || decoder.decode::<u32>() != Some(DISCOVER_OC) {
+                continue;
+            }
+
+            if self.encode_info(&mut encoder, probe) {
+                log!(self.udp.send_to(encoder.data(), addr));
+            }
+        }
+    }
+
+    /// Fills in open sessions until the answer would outgrow `limit`, false
+    /// when not even the bare info fits.
+    fn encode_info(&self, encoder: &mut Encoder, limit: usize) -> bool {
+        let mut info = self.info.clone();
+        for listings in &self.listings {
+            let listings = listings.lock().unwrap();
+            info.sessions.extend(
+                listings
+                    .iter()
+                    .filter(|listing| !listing.is_full())
+                    .cloned(),
+            );
+        }
+
+        loop {
+            let mut data = vec![];
+            info.encode(&mut data);
+            encoder.clear();
+            encoder.encode(&ServerPacket {
+                op_code: DISCOVER_OC,
+                source: Player::invalid(),
+                data,
+            });
+            if encoder.data().len() <= limit {
+                return true;
+            }
+            if info.sessions.pop().is_none() {
+                return false;
+            }
+        }
+    }
+}
+
+/// First frame of a connection, after the optional key exchange.
+pub enum Request {
+    Join(JoinRequestData),
+    List(Vec<SessionFilter>),
+}
+
 pub struct JoinRequest {
     player: PlayerEnt,
     data: JoinRequestData,
@@ -471,29 +1124,77 @@ impl JoinRequest {
 
 pub struct PlayerEnt {
     last_packet: Instant,
-    tcp: TcpStream,
+    // decides who takes over a session when the owner leaves
+    connected_at: Instant,
+    tcp: Box<dyn StreamSocket>,
+    // tcp is nonblocking after joining so frames can arrive in pieces
+    tcp_buffer: Vec<u8>,
+    // frames the socket did not take yet, they go out before anything newer
+    tcp_queue: Vec<u8>,
     udp_addr: Option<SocketAddr>,
+    connection: Connection,
+    token: Token,
+    replay: ReplayWindow,
+    // sequence of the next datagram sent to the player
+    sequence: u64,
+    // set when the player asked for encryption before joining
+    cipher: Option<Cipher>,
+    // from the join request, 0 when the client sent none
+    client_id: u128,
+    // the whole join handshake has to arrive before it, not each read
+    join_deadline: Option<Instant>,
 }
 
 impl PlayerEnt {
-    pub fn new(tcp: TcpStream) -> Self {
+    /// Time a connection has to send its first request, key exchange
+    /// included. The listener serves nobody else meanwhile.
+    pub const JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+    /// Time the oldest reliable udp message may wait for an ack.
+    pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(tcp: Box<dyn StreamSocket>) -> Self {
         Self {
             last_packet: Instant::now(),
+            connected_at: Instant::now(),
             tcp,
+            tcp_buffer: vec![],
+            tcp_queue: vec![],
             udp_addr: None,
+            connection: Connection::new(),
+            token: auth::generate_token(),
+            replay: ReplayWindow::default(),
+            sequence: 0,
+            cipher: None,
+            client_id: 0,
+            join_deadline: None,
+        }
+    }
+
+    /// Only takes addresses on the ip the player joined from, better then
+    /// nothing. Returns whether `addr` was taken.
+    pub fn set_udp_addr(&mut self, addr: SocketAddr) -> bool {
+        if self.ip() != Some(addr.ip()) {
+            return false;
         }
+        self.udp_addr = Some(addr);
+        true
     }
 
-    pub fn set_udp_addr(&mut self, addr: Option<SocketAddr>) -> bool {
-        self.udp_addr = addr;
-        // better then nothing
-        self.tcp.peer_addr().map(|addr| addr.ip()).ok() == addr.map(|addr| addr.ip())
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.tcp.peer_addr().map(|addr| addr.ip()).ok()
     }
 
     pub fn is_inactive(&self) -> bool {
         self.last_packet.elapsed() > Duration::from_secs(60 * 10)
     }
 
+    /// Whether reliable udp messages to the player pile up, tcp traffic
+    /// does not keep it in.
+    pub fn is_unresponsive(&self, now: Instant) -> bool {
+        self.connection.unacked() >= Connection::MAX_PENDING
+            || self.connection.unacked_for(now) > Self::ACK_TIMEOUT
+    }
+
     pub fn collect_tcp_packages(
         &mut self,
         session: Session,
@@ -506,88 +1207,184 @@ impl PlayerEnt {
             match self.recv_tcp(decoder, None) {
                 Ok(_) => {
                     let mut packet = pool.pop().unwrap_or_default();
-                    decoder.decode_into(&mut packet)?;
-                    if packet.session != session || packet.source == this {
+                    packet.clear();
+                    if decoder.decode_into(&mut packet).is_none() {
+                        pool.push(packet);
+                        log!(self.kicked(KickReason::Garbage));
+                        return None;
+                    }
+                    if packet.session != session || packet.source != this {
                         log!("invalid packet: {:?}", packet);
+                        pool.push(packet);
                         continue;
                     }
                     packages.push(packet);
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     if self.is_inactive() {
-                        log!(self.error("Kicking for inactivity!"));
+                        log!(self.kicked(KickReason::Inactivity));
                         return None;
                     } else {
                         return Some(());
                     }
                 }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    log!("player {} disconnected", this.0);
+                    return None;
+                }
                 Err(err) => {
                     log!("{}", err);
-                    log!(self.error("Kicking for sending garbage!"));
+                    log!(self.kicked(KickReason::Garbage));
                     return None;
                 }
             }
         }
     }
 
-    pub fn error(&mut self, message: &str) -> std::io::Result<()> {
-        log!("error sent to {}: {}", self.tcp.peer_addr()?, message);
+    /// Refuses a join or listing request.
+    pub fn reject(&mut self, rejection: Rejection) -> std::io::Result<()> {
+        self.message(&ServerMessage::Rejected(rejection))
+    }
+
+    fn kicked(&mut self, reason: KickReason) -> std::io::Result<()> {
+        self.message(&ServerMessage::Kicked { reason })
+    }
+
+    pub fn message(&mut self, message: &ServerMessage) -> std::io::Result<()> {
+        log!("message sent to {}: {:?}", self.tcp.peer_addr()?, message);
         thread_local! {
-            static ERROR_ENCODER: RefCell<Encoder> = RefCell::new(Encoder::new());
+            static MESSAGE_ENCODER: RefCell<Encoder> = RefCell::new(Encoder::new());
         }
 
-        ERROR_ENCODER.with(|encoder| {
+        MESSAGE_ENCODER.with(|encoder| {
             let mut encoder = encoder.borrow_mut();
             encoder.assert_empty();
-            encoder.encode(&ERROR_OC);
-            encoder.encode_str(message);
-            self.send(&mut *encoder, &None)?;
+            encode_message(&mut encoder, message);
+            let result = self.send(&mut encoder);
             encoder.clear();
-            Ok(())
+            result
         })
     }
 
-    fn send_packet(&mut self, data: &mut Encoder, udp: &Option<&mut UdpSocket>) -> Option<()> {
-        match self.send(data, udp) {
-            Err(err) => {
-                log!("failed to send package: {}", err);
-                None
-            }
-            _ => Some(()),
+    fn send_packet(&mut self, data: &mut Encoder, channel: Option<Channel>) -> Option<()> {
+        let Some(channel) = channel else {
+            return match self.send(data) {
+                Err(err) => {
+                    log!("failed to send package: {}", err);
+                    None
+                }
+                _ => Some(()),
+            };
+        };
+
+        if let Err(e) = self
+            .connection
+            .send(channel, &data.data[Encoder::LEN_SIZE..])
+        {
+            // `SessionEnt::kick_unresponsive` tells the player
+            log!("failed to send package: {}", e);
+            return None;
         }
+        Some(())
     }
 
-    pub fn send(
+    /// Sends over tcp. Leaves the encoder as is so the same data can be
+    /// sent to more players. What the socket does not take right away is
+    /// sent by later calls to `flush_tcp`.
+    pub fn send(&mut self, encoder: &mut Encoder) -> std::io::Result<()> {
+        log!("sending tcp package to {}", self.tcp.peer_addr()?);
+        let frame = match &mut self.cipher {
+            Some(cipher) => cipher.seal_frame(&encoder.data()[Encoder::LEN_SIZE..]),
+            None => encoder.data(),
+        };
+        self.tcp_queue.extend_from_slice(frame);
+        self.flush_tcp()
+    }
+
+    pub fn flush_tcp(&mut self) -> std::io::Result<()> {
+        protocol::flush_tcp(&mut *self.tcp, &mut self.tcp_queue)
+    }
+
+    /// Sends what the udp connection queued, nothing goes out before the
+    /// player registers its address.
+    pub fn flush_udp(
         &mut self,
+        session: Session,
+        udp: &dyn DatagramSocket,
         encoder: &mut Encoder,
-        udp: &Option<&mut UdpSocket>,
+        now: Instant,
     ) -> std::io::Result<()> {
-        if let Some(udp) = udp {
-            if let Some(addr) = self.udp_addr {
-                let err = udp.send_to(encoder.data(), addr);
-                encoder.clear();
-                err?;
+        let Self {
+            udp_addr,
+            connection,
+            token,
+            sequence,
+            cipher,
+            ..
+        } = self;
+        // the channels keep everything until there is somewhere to send it
+        let Some(addr) = *udp_addr else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        connection.flush(now, |frame| {
+            let header = DatagramHeader {
+                session,
+                source: Player::invalid(),
+                sequence: *sequence,
+            };
+            *sequence += 1;
+            match cipher {
+                Some(cipher) => header.encode_sealed(encoder, frame, cipher),
+                None => header.encode_signed(encoder, frame, *token),
+            }
+            if let Err(e) = udp.send_to(encoder.data(), addr) {
+                result = Err(e);
             }
-        } else {
-            log!("sending tcp package to {}", self.tcp.peer_addr()?);
-            let err = self.tcp.write(encoder.data());
             encoder.clear();
-            err?;
+        });
+        result
+    }
+
+    pub fn read_request(&mut self, decoder: &mut Decoder) -> Option<Request> {
+        // big enough for a key exchange, a session name or a few filters
+        const MAX_SIZE: usize =
+            std::mem::size_of::<(u32, JoinRequestData)>() + SessionSettings::MAX_NAME + 32;
+        self.recv_tcp_weak(decoder, Some(MAX_SIZE))?;
+
+        let mut op_code = decoder.decode();
+
+        if op_code == Some(KEY_EXCHANGE_OC) {
+            self.exchange_keys(decoder)?;
+            self.recv_tcp_weak(decoder, Some(MAX_SIZE))?;
+            op_code = decoder.decode();
         }
 
-        Ok(())
+        match op_code? {
+            JOIN_REQUEST_OC => decoder.decode().map(Request::Join),
+            LIST_SESSIONS_OC => decoder.decode().map(Request::List),
+            _ => None,
+        }
     }
 
-    pub fn read_join_request(&mut self, decoder: &mut Decoder) -> Option<JoinRequestData> {
-        self.recv_tcp_weak(decoder, Some(std::mem::size_of::<(u32, JoinRequestData)>()))?;
-
-        let op_code = decoder.decode();
+    fn exchange_keys(&mut self, decoder: &mut Decoder) -> Option<()> {
+        let client_key: Vec<u8> = decoder.decode()?;
+        let handshake = Handshake::new();
+        let mut encoder = Encoder::new();
+        encoder.encode(&ServerPacket {
+            op_code: KEY_EXCHANGE_OC,
+            source: Player::invalid(),
+            data: handshake.public_key(),
+        });
+        let cipher = handshake.finish(Side::Server, &client_key)?;
 
-        if op_code != Some(JOIN_REQUEST_OC) {
+        // the answer itself is not encrypted
+        if let Err(e) = self.send(&mut encoder) {
+            log!("failed to exchange keys: {}", e);
             return None;
         }
-
-        decoder.decode()
+        self.cipher = Some(cipher);
+        Some(())
     }
 
     pub fn recv_tcp_weak(&mut self, decoder: &mut Decoder, max_size: Option<usize>) -> Option<()> {
@@ -600,31 +1397,70 @@ impl PlayerEnt {
         }
     }
 
+    /// Reads the next frame into `decoder`. Fails with `WouldBlock` until
+    /// the whole frame arrived, the part that did is kept for the next call.
+    /// Frames are limited to `max_size` or else `MAX_TCP_FRAME`.
     pub fn recv_tcp(
         &mut self,
         decoder: &mut Decoder,
         max_size: Option<usize>,
     ) -> std::io::Result<()> {
-        let mut size = [0u8; 4];
-        Read::read(&mut self.tcp, &mut size)?;
-        let size = u32::from_le_bytes(size) as usize;
-        if max_size.map(|m| m < size).unwrap_or(false) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "package bigger then expected",
-            ));
-        }
-        self.tcp.read(decoder.expose(size))?;
+        let overhead = self.cipher.as_ref().map_or(0, |_| crypto::TAG_SIZE);
+        let max = max_size.map_or(MAX_TCP_FRAME, |max| max + overhead);
+        let mut chunk = [0; 1024];
+        loop {
+            if self.tcp_buffer.len() >= Encoder::LEN_SIZE {
+                let size = self.frame_size();
+                if size > max {
+                    return Err(protocol::oversized(size, max));
+                }
+                if self.tcp_buffer.len() >= Encoder::LEN_SIZE + size {
+                    break;
+                }
+            }
+
+            if let Some(deadline) = self.join_deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                self.tcp.set_read_timeout(Some(left))?;
+            }
+
+            match self.tcp.read(&mut chunk) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.tcp_buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let size = self.frame_size();
+        decoder
+            .expose(size)
+            .copy_from_slice(&self.tcp_buffer[Encoder::LEN_SIZE..Encoder::LEN_SIZE + size]);
+        self.tcp_buffer.drain(..Encoder::LEN_SIZE + size);
+        if let Some(cipher) = &mut self.cipher {
+            let size = cipher.open_frame(decoder.data_mut()).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "could not decrypt package")
+            })?;
+            decoder.expose(size);
+        }
         self.last_packet = Instant::now();
         Ok(())
     }
 
+    fn frame_size(&self) -> usize {
+        u32::from_le_bytes(self.tcp_buffer[..Encoder::LEN_SIZE].try_into().unwrap()) as usize
+    }
+
     pub fn stop_blocking(&mut self) {
+        self.join_deadline = None;
         log!(self.tcp.set_read_timeout(None));
         log!(self.tcp.set_nonblocking(true));
     }
 
     fn start_join_timeout(&mut self) {
-        log!(self.tcp.set_read_timeout(Some(Duration::from_secs(1))));
+        self.join_deadline = Some(Instant::now() + Self::JOIN_TIMEOUT);
     }
 }
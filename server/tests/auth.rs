@@ -0,0 +1,199 @@
+mod common;
+
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, connect_with, next_packet, IP};
+use server::{
+    auth::Token,
+    channel::{Channel, Frame, Message},
+    client::{Client, Event},
+    link::{DatagramSocket, Direct, Network, StreamSocket},
+    protocol::{Bitwise, DatagramHeader, Encoder, JoinRequestData, Packet, ServerPacket},
+    server::Server,
+};
+
+fn poll_packets(client: &mut Client) -> Vec<ServerPacket> {
+    let mut packets = vec![];
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < deadline {
+        while let Some(event) = client.poll().unwrap() {
+            if let Event::Packet(packet) = event {
+                packets.push(packet);
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    packets
+}
+
+/// Datagram from `client` carrying one unreliable packet for `target`.
+fn datagram(client: &Client, target: &Client, sequence: u64, token: Token) -> Vec<u8> {
+    let packet = Packet {
+        op_code: 7,
+        session: client.session(),
+        source: client.id(),
+        tcp: false,
+        targets: vec![target.id()],
+        data: sequence.to_le_bytes().to_vec(),
+    };
+    let mut message = Message {
+        channel: Channel::Unreliable,
+        ..Default::default()
+    };
+    packet.encode(&mut message.data);
+    let frame = Frame {
+        messages: vec![message],
+        ..Default::default()
+    };
+
+    let header = DatagramHeader {
+        session: client.session(),
+        source: client.id(),
+        sequence,
+    };
+    let mut encoder = Encoder::new();
+    header.encode_signed(&mut encoder, &frame, token);
+    encoder.data().to_vec()
+}
+
+#[test]
+fn forged_and_replayed_datagrams() {
+    let port = common::start(Server::new(1, 120, 0));
+
+    let mut owner = connect(port, JoinRequestData::create(0));
+    let info = *owner.join_info();
+    let mut player = connect(port, JoinRequestData::join(0, info.session, info.thread_id));
+    let token = player.join_info().token;
+
+    let attacker = UdpSocket::bind((IP, 0)).unwrap();
+    let server = (IP, info.udp_port);
+
+    // a wrong token is dropped and does not move the player's address
+    let forged = datagram(&player, &owner, 1000, token ^ 1);
+    attacker.send_to(&forged, server).unwrap();
+    assert!(poll_packets(&mut owner).is_empty());
+    let target = player.id();
+    owner.send_unreliable(8, &[target], b"still yours").unwrap();
+    assert_eq!(poll_packets(&mut player).len(), 1);
+
+    // another ip neither moves the address nor uses up the sequence
+    let signed = datagram(&player, &owner, 2000, token);
+    let elsewhere = UdpSocket::bind(("127.0.0.2", 0)).unwrap();
+    elsewhere.send_to(&signed, server).unwrap();
+    assert!(poll_packets(&mut owner).is_empty());
+    owner.send_unreliable(8, &[target], b"still yours").unwrap();
+    assert_eq!(poll_packets(&mut player).len(), 1);
+
+    // a valid datagram goes through once
+    attacker.send_to(&signed, server).unwrap();
+    let packets = poll_packets(&mut owner);
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].data, 2000u64.to_le_bytes());
+
+    attacker.send_to(&signed, server).unwrap();
+    assert!(poll_packets(&mut owner).is_empty());
+
+    // replays from another ip are not reported to the player
+    poll_packets(&mut player);
+    elsewhere.send_to(&signed, server).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(player.poll().unwrap(), None);
+}
+
+/// While `on` is set datagrams do not leave, they come back to the sender
+/// as if the peer had sent them, like an on-path attacker would do.
+struct Reflector {
+    on: Arc<AtomicBool>,
+}
+
+struct Reflecting {
+    udp: UdpSocket,
+    on: Arc<AtomicBool>,
+    reflected: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Network for Reflector {
+    fn stream(&self, tcp: TcpStream) -> std::io::Result<Box<dyn StreamSocket>> {
+        Direct.stream(tcp)
+    }
+
+    fn datagram(&self, udp: UdpSocket) -> std::io::Result<Box<dyn DatagramSocket>> {
+        udp.set_nonblocking(true)?;
+        Ok(Box::new(Reflecting {
+            udp,
+            on: self.on.clone(),
+            reflected: Mutex::new(VecDeque::new()),
+        }))
+    }
+}
+
+impl DatagramSocket for Reflecting {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        if !self.on.load(Ordering::Relaxed) {
+            return self.udp.send_to(buf, addr);
+        }
+        self.reflected
+            .lock()
+            .unwrap()
+            .push_back((buf.to_vec(), addr));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self.reflected.lock().unwrap().pop_front() {
+            Some((data, addr)) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok((data.len(), addr))
+            }
+            None => self.udp.recv_from(buf),
+        }
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.udp.peek(buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+#[test]
+fn reflected_datagrams() {
+    let port = common::start(Server::new(1, 120, 0));
+
+    let mut owner = connect(port, JoinRequestData::create(0));
+    let info = *owner.join_info();
+    let reflector = Reflector {
+        on: Arc::new(AtomicBool::new(false)),
+    };
+    let join = JoinRequestData::join(0, info.session, info.thread_id);
+    let mut player = connect_with(port, &reflector, false, join).unwrap();
+
+    // the player owes an ack, so its next frame carries one
+    owner
+        .send(Channel::ReliableOrdered, 1, &[player.id()], b"first")
+        .unwrap();
+    assert_eq!(next_packet(&mut player).data, b"first");
+
+    // the reflected frame acks the player's own message, taking it would
+    // drop the message without it ever arriving
+    reflector.on.store(true, Ordering::Relaxed);
+    player
+        .send(Channel::ReliableOrdered, 2, &[owner.id()], b"second")
+        .unwrap();
+    assert!(poll_packets(&mut player).is_empty());
+    reflector.on.store(false, Ordering::Relaxed);
+    thread::sleep(Duration::from_millis(200));
+    poll_packets(&mut player);
+    assert_eq!(next_packet(&mut owner).data, b"second");
+}
@@ -0,0 +1,75 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use server::{
+    client::{self, Client, Event},
+    link::Network,
+    protocol::{JoinRequestData, Rejection, ServerMessage, ServerPacket},
+    server::Server,
+};
+
+pub const IP: &str = "127.0.0.1";
+
+/// Serves `server` on free ports in the background, returns its tcp port.
+pub fn start(mut server: Server) -> u16 {
+    let listener = server.bind().unwrap();
+    let port = server.port();
+    thread::spawn(move || server.serve(listener).unwrap());
+    port
+}
+
+pub fn connect(port: u16, request: JoinRequestData) -> Client {
+    Client::new(IP, port, request).unwrap()
+}
+
+/// Joins through `network`, encrypting the udp traffic when `encrypt` is set.
+pub fn connect_with(
+    port: u16,
+    network: &dyn Network,
+    encrypt: bool,
+    request: JoinRequestData,
+) -> std::io::Result<Client> {
+    Client::with_network(IP, port, request, network, encrypt)
+}
+
+pub fn try_connect(port: u16, request: JoinRequestData) -> Result<Client, Rejection> {
+    Client::new(IP, port, request).map_err(|e| client::rejection(&e).expect("join was rejected"))
+}
+
+pub fn next_any_event(client: &mut Client) -> Event {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while Instant::now() < deadline {
+        if let Some(event) = client.poll().unwrap() {
+            return event;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("no event arrived");
+}
+
+/// Next event that does not announce a join or a leave.
+pub fn next_event(client: &mut Client) -> Event {
+    loop {
+        match next_any_event(client) {
+            Event::Message(ServerMessage::PlayerJoined(_) | ServerMessage::PlayerLeft(_)) => {}
+            event => return event,
+        }
+    }
+}
+
+/// Next packet, skipping every message before it.
+pub fn next_packet(client: &mut Client) -> ServerPacket {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while Instant::now() < deadline {
+        match client.poll().unwrap() {
+            Some(Event::Packet(packet)) => return packet,
+            _ => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    panic!("no packet arrived");
+}
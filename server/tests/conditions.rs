@@ -0,0 +1,134 @@
+mod common;
+
+use std::{
+    io::ErrorKind,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, connect_with};
+use server::{
+    channel::{Channel, Connection},
+    client::{Client, Event},
+    link::{Conditions, LinkConditioner},
+    protocol::{JoinRequestData, ServerMessage},
+    server::Server,
+};
+
+const MESSAGES: u32 = 100;
+
+/// Polls both clients until `until` holds for what the receiver got.
+fn exchange(
+    sender: &mut Client,
+    receiver: &mut Client,
+    until: impl Fn(&[(u32, u32)]) -> bool,
+) -> Vec<(u32, u32)> {
+    let mut received = vec![];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !until(&received) {
+        assert!(Instant::now() < deadline, "got only {:?}", received);
+        sender.poll().unwrap();
+        while let Some(event) = receiver.poll().unwrap() {
+            if let Event::Packet(packet) = event {
+                let value = u32::from_le_bytes(packet.data.try_into().unwrap());
+                received.push((packet.op_code, value));
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    received
+}
+
+#[test]
+fn lossy_jittery_link() {
+    let conditions = Conditions {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(200),
+        loss: 0.2,
+        duplicate: 0.05,
+    };
+    let network = Arc::new(LinkConditioner::new(conditions, 42));
+    let port = common::start(Server::with_network(1, 120, 0, network.clone()));
+
+    let mut owner = connect_with(port, &*network, false, JoinRequestData::create(0)).unwrap();
+    let info = *owner.join_info();
+    let join = JoinRequestData::join(0, info.session, info.thread_id);
+    let mut player = connect_with(port, &*network, false, join).unwrap();
+
+    let channels = [
+        Channel::Unreliable,
+        Channel::UnreliableSequenced,
+        Channel::ReliableUnordered,
+        Channel::ReliableOrdered,
+    ];
+    for i in 0..MESSAGES {
+        for channel in channels {
+            owner
+                .send(channel, channel as u32, &[], &i.to_le_bytes())
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    let received = exchange(&mut owner, &mut player, |received| {
+        let reliable = |channel: Channel| {
+            received
+                .iter()
+                .filter(|(op_code, _)| *op_code == channel as u32)
+                .count()
+        };
+        reliable(Channel::ReliableUnordered) == MESSAGES as usize
+            && reliable(Channel::ReliableOrdered) == MESSAGES as usize
+    });
+    let of = |channel: Channel| {
+        received
+            .iter()
+            .filter(|(op_code, _)| *op_code == channel as u32)
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>()
+    };
+
+    // both hops lose a fifth of the datagrams
+    let unreliable = of(Channel::Unreliable);
+    assert!(unreliable.len() < MESSAGES as usize * 9 / 10);
+    let sequenced = of(Channel::UnreliableSequenced);
+    assert!(sequenced.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let mut unordered = of(Channel::ReliableUnordered);
+    assert_ne!(unordered, (0..MESSAGES).collect::<Vec<_>>());
+    unordered.sort();
+    assert_eq!(unordered, (0..MESSAGES).collect::<Vec<_>>());
+    assert_eq!(
+        of(Channel::ReliableOrdered),
+        (0..MESSAGES).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn never_acking_player() {
+    let port = common::start(Server::new(1, 120, 0));
+    let mut owner = connect(port, JoinRequestData::create(0));
+    let info = *owner.join_info();
+    // keeps tcp open but never polls, so nothing it is sent gets acked
+    let silent = connect(port, JoinRequestData::join(0, info.session, info.thread_id));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut sent = 0;
+    loop {
+        assert!(Instant::now() < deadline, "player was not kicked");
+        if sent <= Connection::MAX_PENDING {
+            match owner.send(Channel::ReliableOrdered, 1, &[silent.id()], &[]) {
+                Ok(()) => sent += 1,
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+        match owner.poll().unwrap() {
+            Some(Event::Message(ServerMessage::PlayerLeft(left))) => {
+                assert_eq!(left, silent.id());
+                break;
+            }
+            _ => thread::sleep(Duration::from_micros(100)),
+        }
+    }
+}
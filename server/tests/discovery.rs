@@ -0,0 +1,99 @@
+mod common;
+
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, IP};
+use server::{
+    client::{self, Client, DiscoveredServer},
+    protocol::{Encoder, JoinRequestData, SessionSettings, DISCOVER_OC, VERSION},
+    server::Server,
+};
+
+fn create(port: u16, name: &str, private: bool) -> Client {
+    let settings = SessionSettings {
+        name: name.to_string(),
+        private,
+        ..Default::default()
+    };
+    connect(port, JoinRequestData::create_with(0, settings))
+}
+
+fn discover_until(
+    port: u16,
+    discovery_port: u16,
+    until: impl Fn(&DiscoveredServer) -> bool,
+) -> DiscoveredServer {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        let servers = client::discover_on(discovery_port, Duration::from_millis(100)).unwrap();
+        let ours = servers.into_iter().find(|server| server.info.port == port);
+        match ours {
+            Some(server) if until(&server) => return server,
+            found => assert!(Instant::now() < deadline, "found {:?}", found),
+        }
+    }
+}
+
+#[test]
+fn discovery_on_loopback() {
+    // whoever answers has to be joinable on the address it answered from
+    let mut server = Server::new(1, 120, 0)
+        .with_address(Ipv4Addr::UNSPECIFIED.into())
+        .with_discovery("LAN party", 0);
+    let listener = server.bind().unwrap();
+    let port = server.port();
+    let discovery_port = server.discovery_port().unwrap();
+    thread::spawn(move || server.serve(listener).unwrap());
+
+    let _owner = create(port, "Open", false);
+    let _hidden = create(port, "Hidden", true);
+
+    let server = discover_until(port, discovery_port, |server| {
+        !server.info.sessions.is_empty()
+    });
+    assert_eq!(server.info.name, "LAN party");
+    assert_eq!(server.info.version, VERSION);
+    let [session] = &server.info.sessions[..] else {
+        panic!("unexpected sessions {:?}", server.info.sessions);
+    };
+    assert_eq!(session.name, "Open");
+
+    // what was discovered is enough to join, whichever address answered
+    let player = Client::new(
+        &server.ip.to_string(),
+        server.info.port,
+        JoinRequestData::join(0, session.session, session.thread_id),
+    )
+    .unwrap();
+    assert_eq!(player.session(), session.session);
+
+    // short probes are ignored, the answer would outgrow them
+    let prober = UdpSocket::bind((IP, 0)).unwrap();
+    prober
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let mut encoder = Encoder::new();
+    encoder.encode(&DISCOVER_OC);
+    prober
+        .send_to(encoder.data(), (IP, discovery_port))
+        .unwrap();
+    assert!(prober.recv_from(&mut [0; 2048]).is_err());
+
+    // nobody answers on other ports
+    let silent = UdpSocket::bind((IP, 0)).unwrap();
+    let silent_port = silent.local_addr().unwrap().port();
+    assert!(client::discover_on(silent_port, Duration::from_millis(100))
+        .unwrap()
+        .is_empty());
+
+    // dropping the server stops its responder and frees the port
+    let mut stopped = Server::new(1, 120, 0).with_discovery("Gone", 0);
+    stopped.bind().unwrap();
+    let port = stopped.discovery_port().unwrap();
+    drop(stopped);
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).unwrap();
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::{connect_with, next_packet};
+use server::{
+    channel::Channel,
+    link::{DatagramSocket, Direct, Network, StreamSocket},
+    protocol::JoinRequestData,
+    server::Server,
+};
+
+const PASSWORD: u128 = 0x5ec2e7_5ec2e7_5ec2e7;
+const SECRET: &[u8] = b"attack at dawn";
+
+/// Keeps a copy of everything the client sends.
+#[derive(Default)]
+struct Recorder {
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Recorder {
+    fn saw(&self, needle: &[u8]) -> bool {
+        let sent = self.sent.lock().unwrap();
+        sent.windows(needle.len()).any(|window| window == needle)
+    }
+}
+
+struct RecordedStream {
+    inner: Box<dyn StreamSocket>,
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for RecordedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for RecordedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.sent.lock().unwrap().extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl StreamSocket for RecordedStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+struct RecordedDatagram {
+    inner: Box<dyn DatagramSocket>,
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl DatagramSocket for RecordedDatagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        self.sent.lock().unwrap().extend_from_slice(buf);
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl Network for Recorder {
+    fn stream(&self, tcp: TcpStream) -> std::io::Result<Box<dyn StreamSocket>> {
+        Ok(Box::new(RecordedStream {
+            inner: Direct.stream(tcp)?,
+            sent: self.sent.clone(),
+        }))
+    }
+
+    fn datagram(&self, udp: UdpSocket) -> std::io::Result<Box<dyn DatagramSocket>> {
+        Ok(Box::new(RecordedDatagram {
+            inner: Direct.datagram(udp)?,
+            sent: self.sent.clone(),
+        }))
+    }
+}
+
+#[test]
+fn encrypted_session() {
+    let port = common::start(Server::new(1, 120, 0));
+
+    let recorder = Recorder::default();
+    let mut owner = connect_with(port, &recorder, true, JoinRequestData::create(PASSWORD)).unwrap();
+    let info = *owner.join_info();
+    let join = |password| JoinRequestData::join(password, info.session, info.thread_id);
+
+    // encrypted and plain players share the session
+    let plain_recorder = Recorder::default();
+    let mut plain = connect_with(port, &plain_recorder, false, join(PASSWORD)).unwrap();
+    let mut encrypted = connect_with(port, &Direct, true, join(PASSWORD)).unwrap();
+    assert!(connect_with(port, &Direct, true, join(PASSWORD + 1)).is_err());
+
+    owner.send_reliable(1, &[], SECRET).unwrap();
+    assert_eq!(next_packet(&mut plain).data, SECRET);
+    assert_eq!(next_packet(&mut encrypted).data, SECRET);
+    for channel in [Channel::Unreliable, Channel::ReliableOrdered] {
+        let target = encrypted.id();
+        owner.send(channel, 2, &[target], SECRET).unwrap();
+        assert_eq!(next_packet(&mut encrypted).data, SECRET);
+    }
+    encrypted.send_reliable(3, &[], b"reply").unwrap();
+    assert_eq!(next_packet(&mut owner).data, b"reply");
+
+    assert!(!recorder.saw(&PASSWORD.to_le_bytes()));
+    assert!(!recorder.saw(SECRET));
+    // the recorder does see plaintext
+    assert!(plain_recorder.saw(&PASSWORD.to_le_bytes()));
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitwise::{Bitwise, Decoder, Encoder};
+use common::{connect, next_packet, IP};
+use server::{
+    protocol::{
+        self, JoinInfo, JoinRequestData, KickReason, Packet, ServerMessage, ServerPacket,
+        JOIN_REQUEST_OC,
+    },
+    server::{PlayerEnt, Server},
+};
+
+/// Joins with a plain socket, so the test decides how frames are cut.
+fn join_raw(port: u16, owner: &JoinInfo) -> (TcpStream, JoinInfo) {
+    let mut tcp = TcpStream::connect((IP, port)).unwrap();
+    let mut encoder = Encoder::new();
+    encoder.encode(&JOIN_REQUEST_OC);
+    encoder.encode(&JoinRequestData::join(0, owner.session, owner.thread_id));
+    tcp.write_all(encoder.data()).unwrap();
+
+    let mut decoder = Decoder::new();
+    protocol::read_tcp_packet_bytes(&mut tcp, &mut decoder).unwrap();
+    let response: ServerPacket = decoder.decode().unwrap();
+    let mut message = ServerMessage::default();
+    message.decode(&mut 0, &response.data).unwrap();
+    let ServerMessage::PlayerJoined(info) = message else {
+        panic!("join failed with {:?}", message);
+    };
+    (tcp, info)
+}
+
+#[test]
+fn frames_in_pieces() {
+    let port = common::start(Server::new(1, 120, 0));
+    let mut owner = connect(port, JoinRequestData::create(0));
+    let (mut raw, info) = join_raw(port, owner.join_info());
+
+    let mut encoder = Encoder::new();
+    encoder.encode(&Packet {
+        op_code: 3,
+        session: info.session,
+        source: info.joined,
+        tcp: true,
+        targets: vec![],
+        data: b"pieces".to_vec(),
+    });
+    // spread over several server frames
+    for byte in encoder.data() {
+        raw.write_all(&[*byte]).unwrap();
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    let packet = next_packet(&mut owner);
+    assert_eq!(
+        (packet.source, packet.data),
+        (info.joined, b"pieces".to_vec())
+    );
+}
+
+#[test]
+fn oversized_frames() {
+    let port = common::start(Server::new(1, 120, 0));
+    let mut owner = connect(port, JoinRequestData::create(0));
+    let error = owner.send_unreliable(1, &[], &[0; 2000]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    // only the length is sent, the server must not wait for the rest
+    let (mut raw, _) = join_raw(port, owner.join_info());
+    let length = protocol::MAX_TCP_FRAME as u32 + 1;
+    raw.write_all(&length.to_le_bytes()).unwrap();
+    let mut decoder = Decoder::new();
+    protocol::read_tcp_packet_bytes(&mut raw, &mut decoder).unwrap();
+    let kicked: ServerPacket = decoder.decode().unwrap();
+    let mut message = ServerMessage::default();
+    message.decode(&mut 0, &kicked.data).unwrap();
+    assert_eq!(
+        message,
+        ServerMessage::Kicked {
+            reason: KickReason::Garbage
+        }
+    );
+    assert_eq!(raw.read(&mut [0]).unwrap(), 0);
+}
+
+#[test]
+fn slow_join() {
+    let port = common::start(Server::new(1, 120, 0));
+    let mut encoder = Encoder::new();
+    encoder.encode(&JOIN_REQUEST_OC);
+    encoder.encode(&JoinRequestData::create(0));
+    let request = encoder.data().to_vec();
+    // each byte arrives well within the timeout, the whole request does not
+    let slow = thread::spawn(move || {
+        let mut tcp = TcpStream::connect((IP, port)).unwrap();
+        for byte in request {
+            if tcp.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(PlayerEnt::JOIN_TIMEOUT / 3);
+        }
+    });
+
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    connect(port, JoinRequestData::create(0));
+    assert!(start.elapsed() < PlayerEnt::JOIN_TIMEOUT * 2);
+    slow.join().unwrap();
+}
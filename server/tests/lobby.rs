@@ -0,0 +1,123 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, try_connect, IP};
+use server::{
+    client::Client,
+    protocol::{JoinRequestData, Rejection, SessionFilter, SessionListing, SessionSettings},
+    server::Server,
+};
+
+fn create(
+    port: u16,
+    password: u128,
+    name: &str,
+    max_players: u32,
+    game_mode: u32,
+    private: bool,
+) -> Client {
+    let settings = SessionSettings {
+        name: name.to_string(),
+        max_players,
+        game_mode,
+        private,
+    };
+    connect(port, JoinRequestData::create_with(password, settings))
+}
+
+fn names(port: u16, filters: &[SessionFilter]) -> Vec<String> {
+    let mut names = Client::list_sessions(IP, port, filters)
+        .unwrap()
+        .into_iter()
+        .map(|listing| listing.name)
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// Listings are published once per server frame.
+fn wait_for(port: u16, until: impl Fn(&[SessionListing]) -> bool) -> Vec<SessionListing> {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        let listings = Client::list_sessions(IP, port, &[]).unwrap();
+        if until(&listings) {
+            return listings;
+        }
+        assert!(Instant::now() < deadline, "got {:?}", listings);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn lobby_listing() {
+    let port = common::start(Server::new(2, 120, 0));
+
+    let duel = create(port, 0, "Duel", 2, 1, false);
+    let _locked = create(port, 7, "Locked Arena", 8, 2, false);
+    let _hidden = create(port, 0, "Hidden", 4, 1, true);
+    let _solo = create(port, 0, "Solo Arena", 1, 2, false);
+
+    let listings = wait_for(port, |listings| listings.len() == 3);
+    let locked = listings
+        .iter()
+        .find(|listing| listing.name == "Locked Arena")
+        .unwrap();
+    assert!(locked.password);
+    assert_eq!(locked.max_players, 8);
+    assert_eq!(locked.players, 1);
+    assert_eq!(locked.game_mode, 2);
+    assert_eq!(names(port, &[]), ["Duel", "Locked Arena", "Solo Arena"]);
+
+    assert_eq!(
+        names(port, &[SessionFilter::NameContains("arena".into())]),
+        ["Locked Arena", "Solo Arena"]
+    );
+    assert_eq!(names(port, &[SessionFilter::GameMode(1)]), ["Duel"]);
+    assert_eq!(
+        names(port, &[SessionFilter::NoPassword]),
+        ["Duel", "Solo Arena"]
+    );
+    assert_eq!(
+        names(port, &[SessionFilter::NotFull]),
+        ["Duel", "Locked Arena"]
+    );
+    assert_eq!(
+        names(port, &[SessionFilter::NotFull, SessionFilter::NoPassword]),
+        ["Duel"]
+    );
+
+    // the listing follows the player count
+    let info = *duel.join_info();
+    // the listener knows which thread runs the session
+    let elsewhere = JoinRequestData::join(0, info.session, 1 - info.thread_id);
+    assert_eq!(
+        try_connect(port, elsewhere).err(),
+        Some(Rejection::SessionNotFound)
+    );
+    let player = connect(port, JoinRequestData::join(0, info.session, info.thread_id));
+    let is_full = |listings: &[SessionListing]| {
+        listings
+            .iter()
+            .any(|listing| listing.session == info.session && listing.players == 2)
+    };
+    wait_for(port, is_full);
+    assert_eq!(names(port, &[SessionFilter::NotFull]), ["Locked Arena"]);
+
+    player.disconnect().unwrap();
+    wait_for(port, |listings| !is_full(listings));
+
+    // closed sessions disappear
+    duel.disconnect().unwrap();
+    wait_for(port, |listings| listings.len() == 2);
+
+    let long = "x".repeat(SessionSettings::MAX_NAME + 1);
+    let settings = SessionSettings {
+        name: long,
+        ..Default::default()
+    };
+    assert!(Client::new(IP, port, JoinRequestData::create_with(0, settings)).is_err());
+}
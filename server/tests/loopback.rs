@@ -0,0 +1,149 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, next_any_event, try_connect};
+use server::{
+    channel::Channel,
+    client::{Client, Event},
+    protocol::{JoinRequestData, KickReason, Rejection, ServerMessage, ServerPacket},
+    server::Server,
+};
+
+const PASSWORD: u128 = 0xdead_beef;
+
+fn assert_silent(client: &mut Client) {
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.poll().unwrap(), None);
+}
+
+fn packet(op_code: u32, client: &Client, data: &[u8]) -> Event {
+    Event::Packet(ServerPacket {
+        op_code,
+        source: client.id(),
+        data: data.to_vec(),
+    })
+}
+
+#[test]
+fn session_over_loopback() {
+    let port = common::start(Server::new(2, 120, 0));
+
+    let mut owner = connect(port, JoinRequestData::create(PASSWORD));
+    let info = *owner.join_info();
+    let join = || JoinRequestData::join(PASSWORD, info.session, info.thread_id);
+    let mut players = (0..3).map(|_| connect(port, join())).collect::<Vec<_>>();
+    for (i, player) in players.iter_mut().enumerate() {
+        assert_eq!(player.session(), info.session);
+        match next_any_event(&mut owner) {
+            Event::Message(ServerMessage::PlayerJoined(joined)) => {
+                // the token is kept from other players
+                assert_ne!(player.join_info().token, 0);
+                assert_eq!(joined.token, 0);
+                assert_eq!(joined.joined, player.id());
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        // later joins are announced to earlier players
+        for _ in i + 1..3 {
+            assert!(matches!(
+                next_any_event(player),
+                Event::Message(ServerMessage::PlayerJoined(_))
+            ));
+        }
+    }
+
+    let wrong = JoinRequestData::join(PASSWORD + 1, info.session, info.thread_id);
+    assert_eq!(
+        try_connect(port, wrong).err(),
+        Some(Rejection::WrongPassword)
+    );
+    let missing = JoinRequestData::join(PASSWORD, info.session, 100);
+    assert_eq!(
+        try_connect(port, missing).err(),
+        Some(Rejection::ThreadNotFound)
+    );
+
+    // reliable broadcast reaches everyone but the sender, in order
+    for i in 0..10u8 {
+        owner.send_reliable(1, &[], &[i; 32]).unwrap();
+    }
+    for player in &mut players {
+        for i in 0..10u8 {
+            assert_eq!(next_any_event(player), packet(1, &owner, &[i; 32]));
+        }
+    }
+    assert_silent(&mut owner);
+
+    // unreliable packet only reaches its target
+    let target = players[1].id();
+    players[0].send_unreliable(2, &[target], b"hello").unwrap();
+    assert_eq!(
+        next_any_event(&mut players[1]),
+        packet(2, &players[0], b"hello")
+    );
+    assert_silent(&mut players[2]);
+    assert_silent(&mut owner);
+
+    assert!(players[0].send_reliable(u32::MAX, &[], &[]).is_err());
+
+    // udp channels are relayed with the same guarantees
+    for i in 0..20u8 {
+        players[0]
+            .send(Channel::ReliableOrdered, 4, &[], &[i])
+            .unwrap();
+    }
+    let (sender, others) = players.split_first_mut().unwrap();
+    for client in others.iter_mut().chain([&mut owner]) {
+        for i in 0..20u8 {
+            assert_eq!(next_any_event(client), packet(4, sender, &[i]));
+        }
+    }
+
+    // only the owner may kick
+    let target = players[2].id();
+    players[0].kick(target).unwrap();
+    assert_eq!(
+        next_any_event(&mut players[0]),
+        Event::Message(ServerMessage::NotOwner)
+    );
+    owner.kick(target).unwrap();
+    assert_eq!(
+        next_any_event(&mut players[2]),
+        Event::Message(ServerMessage::Kicked {
+            reason: KickReason::ByOwner
+        })
+    );
+
+    let mut kicked = players.pop().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while kicked.poll().is_ok() {
+        assert!(
+            Instant::now() < deadline,
+            "kicked player is still connected"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(
+        next_any_event(&mut owner),
+        Event::Message(ServerMessage::PlayerLeft(target))
+    );
+
+    // the session keeps working after a player leaves
+    let left = players.pop().unwrap();
+    let target = left.id();
+    left.disconnect().unwrap();
+    assert_eq!(
+        next_any_event(&mut owner),
+        Event::Message(ServerMessage::PlayerLeft(target))
+    );
+    players[0].send_reliable(3, &[], b"still here").unwrap();
+    assert_eq!(
+        next_any_event(&mut owner),
+        packet(3, &players[0], b"still here")
+    );
+}
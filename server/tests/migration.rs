@@ -0,0 +1,69 @@
+mod common;
+
+use common::{connect, next_event};
+use server::{
+    client::{Client, Event},
+    protocol::{JoinRequestData, KickReason, Player, ServerMessage},
+    server::Server,
+};
+
+fn not_owner() -> Event {
+    Event::Message(ServerMessage::NotOwner)
+}
+
+fn assert_owner(clients: &mut [&mut Client], owner: Player) {
+    for client in clients {
+        assert_eq!(
+            next_event(client),
+            Event::Message(ServerMessage::OwnerChanged(owner))
+        );
+        assert_eq!(client.owner(), owner);
+    }
+}
+
+#[test]
+fn owner_migration() {
+    let port = common::start(Server::new(1, 120, 0));
+
+    let mut first = connect(port, JoinRequestData::create(0));
+    let info = *first.join_info();
+    assert_eq!(info.owner, first.id());
+    let join = || JoinRequestData::join(0, info.session, info.thread_id);
+    let mut second = connect(port, join());
+    let mut third = connect(port, join());
+    assert_eq!(third.owner(), first.id());
+
+    // only the owner hands the session over
+    second.transfer_ownership(second.id()).unwrap();
+    assert_eq!(next_event(&mut second), not_owner());
+    first.transfer_ownership(Player(1000, 0)).unwrap();
+    assert_eq!(
+        next_event(&mut first),
+        Event::Message(ServerMessage::TargetNotFound)
+    );
+
+    let owner = third.id();
+    first.transfer_ownership(owner).unwrap();
+    assert_owner(&mut [&mut first, &mut second, &mut third], owner);
+    first.kick(second.id()).unwrap();
+    assert_eq!(next_event(&mut first), not_owner());
+
+    // the longest connected player takes over
+    third.disconnect().unwrap();
+    let owner = first.id();
+    assert_owner(&mut [&mut first, &mut second], owner);
+    first.disconnect().unwrap();
+    let owner = second.id();
+    assert_owner(&mut [&mut second], owner);
+
+    // and has the authority of an owner
+    let mut fourth = connect(port, join());
+    assert_eq!(fourth.owner(), second.id());
+    second.kick(fourth.id()).unwrap();
+    assert_eq!(
+        next_event(&mut fourth),
+        Event::Message(ServerMessage::Kicked {
+            reason: KickReason::ByOwner
+        })
+    );
+}
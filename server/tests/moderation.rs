@@ -0,0 +1,161 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, next_event, next_packet, try_connect, IP};
+use server::{
+    client::{Client, Event},
+    protocol::{
+        BanScope, JoinPolicy, JoinRequestData, KickReason, Rejection, ServerMessage, Session,
+        SessionListing, SessionSettings,
+    },
+    server::{Server, SessionEnt},
+};
+
+const PASSWORD: u128 = 7;
+const OWNER_ID: u128 = 100;
+
+/// Listings are published once per server frame.
+fn wait_for_listing(port: u16, session: Session, until: impl Fn(Option<&SessionListing>) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        let listings = Client::list_sessions(IP, port, &[]).unwrap();
+        if until(listings.iter().find(|listing| listing.session == session)) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "got {:?}", listings);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn invite(owner: &mut Client) -> u128 {
+    owner.invite().unwrap();
+    match next_event(owner) {
+        Event::Message(ServerMessage::Invite(code)) => code,
+        event => panic!("no invite code, got {:?}", event),
+    }
+}
+
+/// Waits until the server closes the connection of the kicked player.
+fn wait_for_kick(client: &mut Client, reason: KickReason) {
+    assert_eq!(
+        next_event(client),
+        Event::Message(ServerMessage::Kicked { reason })
+    );
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while client.poll().is_ok() {
+        assert!(Instant::now() < deadline, "player was not removed");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn moderation() {
+    let port = common::start(Server::new(1, 120, 0));
+
+    let settings = SessionSettings {
+        name: "Moderated".into(),
+        max_players: 3,
+        ..Default::default()
+    };
+    let create = JoinRequestData::create_with(PASSWORD, settings).with_client_id(OWNER_ID);
+    let mut owner = connect(port, create);
+    let info = *owner.join_info();
+    let join = |password| JoinRequestData::join(password, info.session, info.thread_id);
+
+    assert_eq!(
+        try_connect(port, join(PASSWORD + 1)).err(),
+        Some(Rejection::WrongPassword)
+    );
+    assert_eq!(
+        try_connect(
+            port,
+            JoinRequestData::join(0, Session(1000, 0), info.thread_id)
+        )
+        .err(),
+        Some(Rejection::SessionNotFound)
+    );
+    let mut first = connect(port, join(PASSWORD));
+    let mut second = connect(port, join(PASSWORD));
+    assert_eq!(
+        try_connect(port, join(PASSWORD)).err(),
+        Some(Rejection::SessionFull)
+    );
+
+    // packets from muted players are dropped
+    first.mute(&[second.id()], true).unwrap();
+    assert_eq!(
+        next_event(&mut first),
+        Event::Message(ServerMessage::NotOwner)
+    );
+    owner.mute(&[first.id()], true).unwrap();
+    owner.send_reliable(1, &[second.id()], b"muted").unwrap();
+    assert_eq!(next_packet(&mut second).data, b"muted");
+    first.send_reliable(1, &[second.id()], b"dropped").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    owner.mute(&[first.id()], false).unwrap();
+    owner.send_reliable(1, &[second.id()], b"unmuted").unwrap();
+    assert_eq!(next_packet(&mut second).data, b"unmuted");
+    first.send_reliable(1, &[second.id()], b"heard").unwrap();
+    assert_eq!(next_packet(&mut second).data, b"heard");
+
+    // invite codes replace the password and work once
+    owner.kick(second.id()).unwrap();
+    wait_for_kick(&mut second, KickReason::ByOwner);
+    first.set_join_policy(JoinPolicy::InviteOnly).unwrap();
+    assert_eq!(
+        next_event(&mut first),
+        Event::Message(ServerMessage::NotOwner)
+    );
+    owner.set_join_policy(JoinPolicy::InviteOnly).unwrap();
+    wait_for_listing(port, info.session, |listing| listing.is_none());
+    assert_eq!(
+        try_connect(port, join(PASSWORD)).err(),
+        Some(Rejection::InviteRequired)
+    );
+    let code = invite(&mut owner);
+    let mut invited = connect(port, join(code));
+    owner.kick(invited.id()).unwrap();
+    wait_for_kick(&mut invited, KickReason::ByOwner);
+    assert_eq!(
+        try_connect(port, join(code)).err(),
+        Some(Rejection::InviteRequired)
+    );
+
+    // only the newest codes are kept
+    let codes = (0..=SessionEnt::MAX_INVITES)
+        .map(|_| invite(&mut owner))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        try_connect(port, join(codes[0])).err(),
+        Some(Rejection::InviteRequired)
+    );
+    let mut invited = connect(port, join(codes[SessionEnt::MAX_INVITES]));
+    owner.kick(invited.id()).unwrap();
+    wait_for_kick(&mut invited, KickReason::ByOwner);
+
+    // open sessions ignore the password
+    owner.set_join_policy(JoinPolicy::Open).unwrap();
+    wait_for_listing(port, info.session, |listing| {
+        listing.is_some_and(|listing| !listing.password)
+    });
+    let with_id = |id| join(0).with_client_id(id);
+    let mut anyone = connect(port, with_id(1));
+
+    // bans outlive the connection, an id ban leaves the address alone
+    owner.ban(anyone.id(), BanScope::Id).unwrap();
+    wait_for_kick(&mut anyone, KickReason::Banned);
+    assert_eq!(try_connect(port, with_id(1)).err(), Some(Rejection::Banned));
+    let mut renamed = connect(port, with_id(2));
+
+    // an address ban refuses everyone on it, but for the owner
+    owner.ban(renamed.id(), BanScope::Ip).unwrap();
+    wait_for_kick(&mut renamed, KickReason::Banned);
+    for request in [join(0), with_id(2), with_id(3)] {
+        assert_eq!(try_connect(port, request).err(), Some(Rejection::Banned));
+    }
+    connect(port, with_id(OWNER_ID));
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use store::{Hashing, Invalid, Map, MapOptions};
+
+const KEYS: usize = 10000;
+
+#[derive(Clone, Copy, Default)]
+struct Value(u32);
+
+impl Invalid for Value {
+    fn invalid() -> Self {
+        Value(u32::MAX)
+    }
+
+    fn is_invalid(&self) -> bool {
+        self.0 == u32::MAX
+    }
+}
+
+fn keys() -> Vec<String> {
+    let mut rng = ChaCha8Rng::seed_from_u64(2);
+    (0..KEYS)
+        .map(|_| (0..10).map(|_| rng.gen_range('a'..='z')).collect())
+        .collect()
+}
+
+fn maps() -> [(&'static str, MapOptions); 3] {
+    [
+        ("sdbm", MapOptions::default()),
+        (
+            "fnv1a",
+            MapOptions {
+                hasher: Hashing::Fnv1a,
+                keep_keys: false,
+            },
+        ),
+        (
+            "sdbm kept keys",
+            MapOptions {
+                hasher: Hashing::Sdbm,
+                keep_keys: true,
+            },
+        ),
+    ]
+}
+
+fn filled(keys: &[String], options: MapOptions) -> Map<Value> {
+    let mut map = Map::with_options(0, options);
+    for (i, key) in keys.iter().enumerate() {
+        map.insert(key, Value(i as u32));
+    }
+    map
+}
+
+fn insert(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("insert");
+    for (name, options) in maps() {
+        group.bench_function(name, |b| b.iter(|| filled(&keys, options)));
+    }
+    group.bench_function("std", |b| {
+        b.iter(|| {
+            let mut map = HashMap::new();
+            for (i, key) in keys.iter().enumerate() {
+                map.insert(key.clone(), i as u32);
+            }
+            map
+        })
+    });
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("lookup");
+    for (name, options) in maps() {
+        let map = filled(&keys, options);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                keys.iter()
+                    .filter_map(|key| map.get(key))
+                    .map(|v| v.0)
+                    .sum::<u32>()
+            })
+        });
+    }
+    let map = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key.clone(), i as u32))
+        .collect::<HashMap<_, _>>();
+    group.bench_function("std", |b| {
+        b.iter(|| keys.iter().filter_map(|key| map.get(key)).sum::<u32>())
+    });
+    group.finish();
+}
+
+fn remove(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("remove");
+    for (name, options) in maps() {
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || filled(&keys, options),
+                |map| {
+                    for key in &keys {
+                        black_box(map.remove(key));
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn entry(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("count with entry");
+    group.bench_function("entry", |b| {
+        b.iter(|| {
+            let mut map = Map::<Value>::new();
+            for key in keys.iter().chain(&keys) {
+                map.entry(key).or_default().0 += 1;
+            }
+            map
+        })
+    });
+    group.bench_function("get then insert", |b| {
+        b.iter(|| {
+            let mut map = Map::<Value>::new();
+            for key in keys.iter().chain(&keys) {
+                let count = map.get(key).copied().unwrap_or_default();
+                map.insert(key, Value(count.0 + 1));
+            }
+            map
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert, lookup, remove, entry);
+criterion_main!(benches);
@@ -0,0 +1,226 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+use crate::{Access, PoolStore};
+
+/// `PoolStore` that can be shared between threads. Values are spread over
+/// independently locked shards, the shard is encoded in the handle index so
+/// lookups lock only one of them. Handles stay generational.
+pub struct ConcurrentPoolStore<A: Access, T> {
+    shards: Box<[RwLock<PoolStore<A, T>>]>,
+    next: AtomicUsize,
+}
+
+impl<A: Access, T> ConcurrentPoolStore<A, T> {
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    pub fn new() -> Self {
+        Self::with_shards(Self::DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "at least one shard is needed");
+        Self {
+            shards: (0..shards).map(|_| RwLock::default()).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, t: T) -> A {
+        let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let mut store = self.write(shard);
+        let local = store.push(t);
+        // handles hold the index in a u32 with u32::MAX marking invalid ones,
+        // wrapping would alias them
+        let index = local
+            .index()
+            .checked_mul(self.shards.len())
+            .and_then(|index| index.checked_add(shard))
+            .filter(|&index| index < u32::MAX as usize);
+        let Some(index) = index else {
+            store.remove(local);
+            drop(store);
+            panic!("too many values for the handle index, use fewer shards");
+        };
+        A::with_generation(index, local.generation())
+    }
+
+    pub fn remove(&self, a: A) -> T {
+        self.try_remove(a).expect("removing with stale handle")
+    }
+
+    pub fn try_remove(&self, a: A) -> Option<T> {
+        let (shard, local) = self.locate(a);
+        self.write(shard).try_remove(local)
+    }
+
+    pub fn contains(&self, a: A) -> bool {
+        let (shard, local) = self.locate(a);
+        self.read(shard).contains(local)
+    }
+
+    /// Runs `f` on the value while its shard is read locked.
+    pub fn with<R>(&self, a: A, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let (shard, local) = self.locate(a);
+        self.read(shard).get(local).map(f)
+    }
+
+    /// Runs `f` on the value while its shard is write locked.
+    pub fn with_mut<R>(&self, a: A, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let (shard, local) = self.locate(a);
+        self.write(shard).get_mut(local).map(f)
+    }
+
+    pub fn get(&self, a: A) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.with(a, T::clone)
+    }
+
+    /// Not a snapshot, shards are counted one after another.
+    pub fn count(&self) -> usize {
+        (0..self.shards.len())
+            .map(|shard| self.read(shard).count())
+            .sum()
+    }
+
+    /// Visits values shard by shard, holding one read lock at a time.
+    pub fn for_each(&self, mut f: impl FnMut(A, &T)) {
+        for shard in 0..self.shards.len() {
+            for (local, t) in self.read(shard).iter() {
+                let a = A::with_generation(
+                    local.index() * self.shards.len() + shard,
+                    local.generation(),
+                );
+                f(a, t);
+            }
+        }
+    }
+
+    pub fn into_shards(self) -> Vec<PoolStore<A, T>> {
+        self.shards
+            .into_vec()
+            .into_iter()
+            .map(|shard| shard.into_inner().unwrap_or_else(PoisonError::into_inner))
+            .collect()
+    }
+
+    fn locate(&self, a: A) -> (usize, A) {
+        let shards = self.shards.len();
+        (
+            a.index() % shards,
+            A::with_generation(a.index() / shards, a.generation()),
+        )
+    }
+
+    // a panic while holding the lock cannot leave a PoolStore half updated
+    // in a way that breaks other slots, so poisoning is ignored
+
+    fn read(&self, shard: usize) -> RwLockReadGuard<'_, PoolStore<A, T>> {
+        self.shards[shard]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self, shard: usize) -> RwLockWriteGuard<'_, PoolStore<A, T>> {
+        self.shards[shard]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<A: Access, T> Default for ConcurrentPoolStore<A, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Barrier, thread};
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use crate::*;
+
+    create_access!(Id);
+
+    #[test]
+    fn single_thread() {
+        let store = ConcurrentPoolStore::<Id, u32>::with_shards(3);
+        let ids = (0..10).map(|i| store.push(i)).collect::<Vec<_>>();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(store.get(id), Some(i as u32));
+        }
+        assert_eq!(store.remove(ids[4]), 4);
+        let reused = store.push(40);
+        assert_eq!(reused.index(), ids[4].index());
+        assert!(!store.contains(ids[4]));
+        assert_eq!(store.try_remove(ids[4]), None);
+        assert_eq!(store.with_mut(reused, |value| *value += 2), Some(()));
+        assert_eq!(store.get(reused), Some(42));
+        assert_eq!(store.get(Id::invalid()), None);
+        assert_eq!(store.count(), 10);
+
+        let mut sum = 0;
+        store.for_each(|id, &value| {
+            assert_eq!(store.get(id), Some(value));
+            sum += value;
+        });
+        assert_eq!(sum, 45 - 4 + 42);
+    }
+
+    #[test]
+    fn stress() {
+        const THREADS: u64 = 8;
+        const OPERATIONS: usize = 20000;
+
+        let store = ConcurrentPoolStore::<Id, (u64, usize)>::with_shards(4);
+        let barrier = Barrier::new(THREADS as usize);
+
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let store = &store;
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    let mut rng = ChaCha8Rng::seed_from_u64(thread);
+                    let mut owned = vec![];
+                    let mut removed = vec![];
+                    barrier.wait();
+                    for op in 0..OPERATIONS {
+                        if owned.is_empty() || rng.gen_bool(0.6) {
+                            owned.push((store.push((thread, op)), op));
+                        } else {
+                            let (id, op) = owned.swap_remove(rng.gen_range(0..owned.len()));
+                            assert_eq!(store.try_remove(id), Some((thread, op)));
+                            removed.push(id);
+                        }
+
+                        // other threads must never see or reuse our values
+                        if let Some(&(id, op)) = owned.last() {
+                            assert_eq!(store.get(id), Some((thread, op)));
+                        }
+                        if let Some(&id) = removed.last() {
+                            if let Some((owner, _)) = store.get(id) {
+                                panic!("stale handle resolved to value of thread {}", owner);
+                            }
+                        }
+                    }
+
+                    for (id, op) in owned {
+                        assert_eq!(store.get(id), Some((thread, op)));
+                    }
+                });
+            }
+        });
+
+        let mut per_thread = vec![0; THREADS as usize];
+        store.for_each(|_, &(thread, _)| per_thread[thread as usize] += 1);
+        assert_eq!(per_thread.iter().sum::<usize>(), store.count());
+        assert!(per_thread.iter().all(|&count| count > 0));
+    }
+}
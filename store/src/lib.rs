@@ -1,76 +1,221 @@
 use std::{
     marker::PhantomData,
-    ops::{Deref, DerefMut, Index, IndexMut},
+    ops::{Deref, Index, IndexMut},
 };
 
 pub use bitwise::*;
+pub use concurrent::ConcurrentPoolStore;
+pub use sparse_set::SparseSet;
+pub use tracked::{Change, ChangeLog, TrackedPoolStore, TrackedStore};
 
-pub struct Table<A: Access + Invalid, T: Invalid> {
+mod concurrent;
+mod sparse_set;
+mod tracked;
+
+/// Named values. Removed names free their slot and the handles pointing to
+/// it become stale, see `PoolStore`.
+pub struct Table<A: Access + Invalid, T> {
     lookup: Map<A>,
-    data: Store<A, T>,
+    names: Vec<Identifier>,
+    data: PoolStore<A, T>,
 }
 
-impl<A: Access + Invalid, T: Invalid> Table<A, T> {
+impl<A: Access + Invalid, T> Table<A, T> {
     pub fn new() -> Self {
         Self::default()
     }
 
     pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_options(capacity, MapOptions::default())
+    }
+
+    pub fn with_options(capacity: usize, options: MapOptions) -> Self {
         Self {
-            lookup: Map::with_capacity(capacity),
-            data: Store::with_capacity(capacity),
+            lookup: Map::with_options(capacity, options),
+            names: Vec::with_capacity(capacity),
+            data: PoolStore::with_capacity(capacity),
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<&T> {
-        self.get_by_id(Identifier::new(key))
+    pub fn get<K: Key>(&self, key: K) -> Option<&T> {
+        self.access(key).and_then(|idx| self.data.get(idx))
     }
 
     pub fn get_by_id(&self, id: Identifier) -> Option<&T> {
-        self.lookup.get_by_id(id).map(|&idx| &self.data[idx])
+        self.get(id)
+    }
+
+    pub fn get_mut<K: Key>(&mut self, key: K) -> Option<&mut T> {
+        let idx = self.access(key)?;
+        self.data.get_mut(idx)
+    }
+
+    pub fn contains<K: Key>(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the handle of `key`, same as `insert`.
+    pub fn access<K: Key>(&self, key: K) -> Option<A> {
+        self.lookup.get(key).copied()
+    }
+
+    /// Reverse lookup, `None` if `a` is stale.
+    pub fn name_of(&self, a: A) -> Option<Identifier> {
+        if self.data.is_valid(a) {
+            Some(self.names[a.index()])
+        } else {
+            None
+        }
+    }
+
+    /// Inserts the value or overwrites the existing one under the same name.
+    /// Overwriting keeps the handle. Panics on collision, see `try_insert`.
+    pub fn insert<K: Key>(&mut self, key: K, value: T) -> A {
+        self.try_insert(key, value)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Fails if the table keeps keys and `key` collides with another one.
+    pub fn try_insert<K: Key>(&mut self, key: K, value: T) -> Result<A, Collision> {
+        if let Some(&idx) = self.lookup.try_get(&key)? {
+            self.data[idx] = value;
+            return Ok(idx);
+        }
+
+        let idx = self.data.push(value);
+        if self.names.len() <= idx.index() {
+            self.names.resize(idx.index() + 1, Identifier::invalid());
+        }
+        self.names[idx.index()] = self.lookup.identify(&key);
+        self.lookup.try_insert(key, idx)?;
+        Ok(idx)
+    }
+
+    pub fn insert_by_id(&mut self, id: Identifier, value: T) -> A {
+        self.insert(id, value)
+    }
+
+    /// Like `insert` but hands back the overwritten value.
+    pub fn replace<K: Key>(&mut self, key: K, value: T) -> Option<T> {
+        match self.get_mut(&key) {
+            Some(current) => Some(std::mem::replace(current, value)),
+            None => {
+                self.insert(key, value);
+                None
+            }
+        }
+    }
+
+    pub fn remove<K: Key>(&mut self, key: K) -> Option<T> {
+        let idx = self.lookup.remove(key)?;
+        self.names[idx.index()] = Identifier::invalid();
+        Some(self.data.remove(idx))
     }
 
-    pub fn insert(&mut self, key: &str, value: T) {
-        self.insert_by_id(Identifier::new(key), value);
+    /// Original key, only available when the table keeps keys.
+    pub fn key_of(&self, a: A) -> Option<&str> {
+        self.lookup.key_of(self.name_of(a)?)
     }
 
-    pub fn insert_by_id(&mut self, id: Identifier, value: T) {
-        let key = self.data.push(value);
-        self.lookup.insert_by_id(id, key);
+    pub fn try_remove(&mut self, a: A) -> Option<T> {
+        let id = self.name_of(a)?;
+        self.remove(id)
+    }
+
+    pub fn iter_named(&self) -> impl Iterator<Item = (Identifier, A, &T)> {
+        self.data
+            .iter()
+            .map(|(key, value)| (self.names[key.index()], key, value))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.data.values_mut()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A, &mut T)> {
+        self.data.iter_mut()
     }
 
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.data.count()
     }
 }
 
-impl<A: Access + Invalid, T: Invalid> Default for Table<A, T> {
+impl<A: Access + Invalid, T> Default for Table<A, T> {
     fn default() -> Self {
         Self {
             lookup: Map::default(),
-            data: Store::default(),
+            names: Vec::new(),
+            data: PoolStore::default(),
         }
     }
 }
 
-impl<A: Access + Invalid, T: Invalid> Deref for Table<A, T> {
-    type Target = Store<A, T>;
+/// Ids stay the same after a round-trip.
+impl<A: Access + Invalid + Bitwise + Default, T: Bitwise + Default> Bitwise for Table<A, T> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.lookup.encode(buffer);
+        self.names.encode(buffer);
+        self.data.encode(buffer);
+    }
+
+    fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
+        let mut table = Self::new();
+        table.lookup.decode(cursor, buffer)?;
+        table.names.decode(cursor, buffer)?;
+        table.data.decode(cursor, buffer)?;
+
+        // names and handles must describe the same entries
+        let mut named = 0;
+        for (id, &a) in table.lookup.iter().filter(|(_, a)| !a.is_invalid()) {
+            if !table.data.is_valid(a) || table.names.get(a.index()) != Some(&id) {
+                return None;
+            }
+            named += 1;
+        }
+        if named != table.data.count() {
+            return None;
+        }
+
+        *self = table;
+        Some(())
+    }
+}
+
+/// Mutable access goes through the table so names cannot get out of sync
+/// with the values.
+impl<A: Access + Invalid, T> Deref for Table<A, T> {
+    type Target = PoolStore<A, T>;
 
     fn deref(&self) -> &Self::Target {
         &self.data
     }
 }
 
-impl<A: Access + Invalid, T: Invalid> DerefMut for Table<A, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.data
+impl<A: Access + Invalid, T> Index<A> for Table<A, T> {
+    type Output = T;
+
+    fn index(&self, index: A) -> &Self::Output {
+        &self.data[index]
     }
 }
 
-pub struct Map<T: Invalid> {
+impl<A: Access + Invalid, T> IndexMut<A> for Table<A, T> {
+    fn index_mut(&mut self, index: A) -> &mut Self::Output {
+        &mut self.data[index]
+    }
+}
+
+/// Hash map keyed by `Identifier`. Keys of any `Key` type are hashed with
+/// `H` and only hashes are compared, unless the map keeps its keys, then
+/// string lookups are verified and colliding inserts are reported.
+pub struct Map<T: Invalid, H: KeyHasher = Hashing> {
     lookup: Vec<u32>,
     data: Vec<(Identifier, T, u32)>,
+    keys: Vec<Option<Box<str>>>,
+    options: MapOptions<H>,
     free: u32,
+    len: usize,
 }
 
 impl<T: Invalid> Map<T> {
@@ -79,65 +224,155 @@ impl<T: Invalid> Map<T> {
     }
 
     pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_options(capacity, MapOptions::default())
+    }
+}
+
+impl<T: Invalid, H: KeyHasher> Map<T, H> {
+    pub fn with_hasher(capacity: usize, hasher: H) -> Self {
+        Self::with_options(
+            capacity,
+            MapOptions {
+                hasher,
+                keep_keys: false,
+            },
+        )
+    }
+
+    pub fn with_options(capacity: usize, options: MapOptions<H>) -> Self {
         Self {
             lookup: vec![u32::MAX; Self::best_size(capacity)],
             data: Vec::with_capacity(capacity),
+            keys: Vec::new(),
+            options,
             free: u32::MAX,
+            len: 0,
         }
     }
 
-    pub fn remove(&mut self, key: &str) -> Option<T> {
-        self.remove_by_id(Identifier::new(key))
+    pub fn options(&self) -> &MapOptions<H> {
+        &self.options
+    }
+
+    /// Hashes the key the way this map does.
+    pub fn identify<K: Key>(&self, key: K) -> Identifier {
+        key.identify(&self.options.hasher)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Original key of the entry, `None` if keys are not kept or the entry
+    /// was inserted by id.
+    pub fn key_of(&self, id: Identifier) -> Option<&str> {
+        self.find(id)
+            .and_then(|entry| self.keys.get(entry)?.as_deref())
+    }
+
+    pub fn remove<K: Key>(&mut self, key: K) -> Option<T> {
+        let entry = self.locate(&key).ok()??;
+        Some(self.remove_entry(entry))
     }
 
     pub fn remove_by_id(&mut self, id: Identifier) -> Option<T> {
-        let index = self.index_of(id);
+        self.remove(id)
+    }
+
+    fn remove_entry(&mut self, entry: usize) -> T {
+        let index = self.index_of(self.data[entry].0);
         let mut current = self.lookup[index];
         let mut last_id = u32::MAX;
-        while current != u32::MAX {
-            let (identifier, value, next) = &mut self.data[current as usize];
-
-            if *identifier == id && !value.is_invalid() {
-                let saved_next = *next;
-                *next = self.free as u32;
-                let value = std::mem::replace(value, T::invalid());
-                if last_id == u32::MAX {
-                    self.lookup[index] = saved_next;
-                } else {
-                    self.data[last_id as usize].2 = saved_next;
-                }
-                self.free = current;
-                return Some(value);
-            }
-
+        while current as usize != entry {
             last_id = current;
-            current = *next;
+            current = self.data[current as usize].2;
         }
 
-        None
+        let (_, value, next) = &mut self.data[entry];
+        let saved_next = std::mem::replace(next, self.free);
+        let value = std::mem::replace(value, T::invalid());
+        if last_id == u32::MAX {
+            self.lookup[index] = saved_next;
+        } else {
+            self.data[last_id as usize].2 = saved_next;
+        }
+        if let Some(key) = self.keys.get_mut(entry) {
+            *key = None;
+        }
+        self.free = current;
+        self.len -= 1;
+        value
     }
 
-    pub fn insert(&mut self, id: &str, t: T) -> Option<T> {
-        self.insert_by_id(Identifier::new(id), t)
+    /// Panics if the key collides with a different kept key, see
+    /// `try_insert`.
+    pub fn insert<K: Key>(&mut self, key: K, t: T) -> Option<T> {
+        self.try_insert(key, t)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_insert<K: Key>(&mut self, key: K, t: T) -> Result<Option<T>, Collision> {
+        Ok(match self.try_entry(key)? {
+            Entry::Occupied(mut entry) => Some(entry.insert(t)),
+            Entry::Vacant(entry) => {
+                entry.insert(t);
+                None
+            }
+        })
     }
 
     pub fn insert_by_id(&mut self, id: Identifier, t: T) -> Option<T> {
-        let index = self.index_of(id);
-        let mut current = self.lookup[index];
+        self.insert(id, t)
+    }
 
-        let mut last_id = u32::MAX;
+    /// Hashes the key once for a lookup followed by an insertion. Panics on
+    /// collision, see `try_entry`.
+    pub fn entry<K: Key>(&mut self, key: K) -> Entry<'_, T, H> {
+        self.try_entry(key).unwrap_or_else(|err| panic!("{}", err))
+    }
 
-        while current != u32::MAX {
-            let (identifier, data, next) = &mut self.data[current as usize];
+    pub fn try_entry<K: Key>(&mut self, key: K) -> Result<Entry<'_, T, H>, Collision> {
+        // `locate` compares kept keys, they are only written on creation
+        if let Some(entry) = self.locate(&key)? {
+            return Ok(Entry::Occupied(OccupiedEntry { map: self, entry }));
+        }
 
-            if data.is_invalid() {
-                *identifier = id;
-                *data = t;
-                return None;
-            } else if id == *identifier {
-                return Some(std::mem::replace(data, t));
-            };
+        let name = key.name().filter(|_| self.options.keep_keys);
+        Ok(Entry::Vacant(VacantEntry {
+            id: self.identify(&key),
+            key: name.map(Into::into),
+            map: self,
+        }))
+    }
 
+    /// Fails if a value is already stored under `id`.
+    fn push_entry(
+        &mut self,
+        id: Identifier,
+        key: Option<Box<str>>,
+        t: T,
+    ) -> Result<usize, Collision> {
+        if self.free == u32::MAX && self.data.len() >= self.lookup.len() {
+            self.expand();
+        }
+
+        let index = self.index_of(id);
+        let mut current = self.lookup[index];
+        let mut last_id = u32::MAX;
+        while current != u32::MAX {
+            let (other, value, next) = &self.data[current as usize];
+            if *other == id && !value.is_invalid() {
+                let existing = self.keys.get(current as usize).cloned().flatten();
+                return Err(Collision {
+                    id,
+                    existing: existing.unwrap_or_default().into(),
+                    key: key.unwrap_or_default().into(),
+                });
+            }
             last_id = current;
             current = *next;
         }
@@ -152,61 +387,144 @@ impl<T: Invalid> Map<T> {
             free
         };
 
+        if self.options.keep_keys {
+            if self.keys.len() <= new as usize {
+                self.keys.resize(new as usize + 1, None);
+            }
+            self.keys[new as usize] = key;
+        }
+
         if last_id == u32::MAX {
             self.lookup[index] = new;
         } else {
             self.data[last_id as usize].2 = new;
         }
+        self.len += 1;
 
-        if self.data.len() > self.lookup.len() {
-            self.expand();
-        }
-
-        None
+        Ok(new as usize)
     }
 
     #[cold]
     fn expand(&mut self) {
-        let mut new = Self::with_capacity(self.data.len());
+        self.rebuild(self.data.len() + 1);
+    }
+
+    fn rebuild(&mut self, capacity: usize) {
+        let mut new = Self::with_options(capacity, self.options.clone());
+        let mut keys = std::mem::take(&mut self.keys).into_iter();
 
-        for (id, t, _) in self.data.drain(..).filter(|(_, t, _)| !t.is_invalid()) {
-            new.insert_by_id(id, t);
+        for (id, t, _) in self.data.drain(..) {
+            let key = keys.next().flatten();
+            if !t.is_invalid() {
+                new.push_entry(id, key, t)
+                    .expect("rebuilt entries are unique");
+            }
         }
 
         *self = new;
     }
 
-    pub fn get(&self, name: &str) -> Option<&T> {
-        self.get_by_id(Identifier::new(name))
+    pub fn get<K: Key>(&self, key: K) -> Option<&T> {
+        self.try_get(key).ok().flatten()
     }
 
-    pub fn get_by_id(&self, id: Identifier) -> Option<&T> {
-        let index = self.index_of(id);
-        let mut current = self.lookup[index as usize];
+    /// Tells a missing key apart from one that collides with a kept key.
+    pub fn try_get<K: Key>(&self, key: K) -> Result<Option<&T>, Collision> {
+        Ok(self.locate(&key)?.map(|entry| &self.data[entry].1))
+    }
 
-        while current != u32::MAX {
-            let (ident, data, next) = &self.data[current as usize];
-            if *ident == id && !data.is_invalid() {
-                return Some(data);
-            }
-            current = *next;
-        }
+    pub fn get_mut<K: Key>(&mut self, key: K) -> Option<&mut T> {
+        let entry = self.locate(&key).ok()??;
+        Some(&mut self.data[entry].1)
+    }
 
-        None
+    pub fn get_by_id(&self, id: Identifier) -> Option<&T> {
+        self.get(id)
+    }
+
+    pub fn contains<K: Key>(&self, key: K) -> bool {
+        self.get(key).is_some()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (Identifier, &T)> {
-        self.data.iter().map(|(id, t, _)| (*id, t))
+        self.data
+            .iter()
+            .filter(|(_, t, _)| !t.is_invalid())
+            .map(|(id, t, _)| (*id, t))
     }
 
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (Identifier, &mut T)> {
-        self.data.iter_mut().map(|(id, t, _)| (*id, t))
+        self.data
+            .iter_mut()
+            .filter(|(_, t, _)| !t.is_invalid())
+            .map(|(id, t, _)| (*id, t))
+    }
+
+    /// Removes the entries `f` returns false for.
+    pub fn retain(&mut self, mut f: impl FnMut(Identifier, &mut T) -> bool) {
+        // removal leaves entries in place so indices stay valid
+        for entry in 0..self.data.len() {
+            let (id, t, _) = &mut self.data[entry];
+            if !t.is_invalid() && !f(*id, t) {
+                self.remove_entry(entry);
+            }
+        }
+    }
+
+    /// Empties the map, the capacity is kept.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Identifier, T)> + '_ {
+        self.lookup.iter_mut().for_each(|x| *x = u32::MAX);
+        self.keys.clear();
+        self.free = u32::MAX;
+        self.len = 0;
+        self.data
+            .drain(..)
+            .filter(|(_, t, _)| !t.is_invalid())
+            .map(|(id, t, _)| (id, t))
+    }
+
+    /// Drops removed entries and shrinks the buckets to the current length.
+    pub fn shrink_to_fit(&mut self) {
+        self.rebuild(self.len);
+        self.data.shrink_to_fit();
+        self.keys.shrink_to_fit();
     }
 
     pub fn clear(&mut self) {
         self.lookup.iter_mut().for_each(|x| *x = u32::MAX);
         self.data.clear();
+        self.keys.clear();
         self.free = u32::MAX;
+        self.len = 0;
+    }
+
+    fn find(&self, id: Identifier) -> Option<usize> {
+        let mut current = self.lookup[self.index_of(id)];
+
+        while current != u32::MAX {
+            let (ident, data, next) = &self.data[current as usize];
+            if *ident == id && !data.is_invalid() {
+                return Some(current as usize);
+            }
+            current = *next;
+        }
+
+        None
+    }
+
+    fn locate<K: Key>(&self, key: &K) -> Result<Option<usize>, Collision> {
+        let Some(entry) = self.find(self.identify(key)) else {
+            return Ok(None);
+        };
+
+        match (self.keys.get(entry), key.name()) {
+            (Some(Some(existing)), Some(key)) if &**existing != key => Err(Collision {
+                id: self.data[entry].0,
+                existing: existing.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Ok(Some(entry)),
+        }
     }
 
     fn index_of(&self, ident: Identifier) -> usize {
@@ -218,20 +536,326 @@ impl<T: Invalid> Map<T> {
     }
 }
 
-impl<T: Invalid> Default for Map<T> {
+impl<T: Invalid, H: KeyHasher + Default> Default for Map<T, H> {
     fn default() -> Self {
         Self {
             lookup: vec![u32::MAX],
             data: Vec::new(),
+            keys: Vec::new(),
+            options: MapOptions::default(),
             free: u32::MAX,
+            len: 0,
+        }
+    }
+}
+
+impl<T: Invalid + Bitwise + Default, H: KeyHasher + Bitwise + Default> Bitwise for Map<T, H> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.options.encode(buffer);
+        let live = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, t, _))| !t.is_invalid());
+        live.clone().count().encode(buffer);
+        for (entry, (id, t, _)) in live {
+            id.encode(buffer);
+            if self.options.keep_keys {
+                match self.keys.get(entry).and_then(Option::as_deref) {
+                    Some(key) => {
+                        true.encode(buffer);
+                        key.to_string().encode(buffer);
+                    }
+                    None => false.encode(buffer),
+                }
+            }
+            t.encode(buffer);
+        }
+    }
+
+    fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
+        let mut options = MapOptions::<H>::default();
+        options.decode(cursor, buffer)?;
+        let mut len = 0usize;
+        len.decode(cursor, buffer)?;
+
+        // prevents injected huge allocations
+        if len > buffer.len() - *cursor {
+            return None;
+        }
+
+        let keep_keys = options.keep_keys;
+        let mut map = Self::with_options(len, options);
+        for _ in 0..len {
+            let mut id = Identifier::invalid();
+            id.decode(cursor, buffer)?;
+            let mut key = None;
+            if keep_keys {
+                let mut has_key = false;
+                has_key.decode(cursor, buffer)?;
+                if has_key {
+                    let mut name = String::new();
+                    name.decode(cursor, buffer)?;
+                    key = Some(name.into_boxed_str());
+                }
+            }
+            let mut t = T::default();
+            t.decode(cursor, buffer)?;
+            if t.is_invalid() {
+                return None;
+            }
+            map.push_entry(id, key, t).ok()?;
+        }
+
+        *self = map;
+        Some(())
+    }
+}
+
+pub enum Entry<'a, T: Invalid, H: KeyHasher> {
+    Occupied(OccupiedEntry<'a, T, H>),
+    Vacant(VacantEntry<'a, T, H>),
+}
+
+impl<'a, T: Invalid, H: KeyHasher> Entry<'a, T, H> {
+    pub fn id(&self) -> Identifier {
+        match self {
+            Self::Occupied(entry) => entry.id(),
+            Self::Vacant(entry) => entry.id(),
+        }
+    }
+
+    pub fn or_insert(self, t: T) -> &'a mut T {
+        self.or_insert_with(|| t)
+    }
+
+    pub fn or_insert_with(self, f: impl FnOnce() -> T) -> &'a mut T {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut T
+    where
+        T: Default,
+    {
+        self.or_insert_with(T::default)
+    }
+
+    pub fn and_modify(mut self, f: impl FnOnce(&mut T)) -> Self {
+        if let Self::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+pub struct OccupiedEntry<'a, T: Invalid, H: KeyHasher> {
+    map: &'a mut Map<T, H>,
+    entry: usize,
+}
+
+impl<'a, T: Invalid, H: KeyHasher> OccupiedEntry<'a, T, H> {
+    pub fn id(&self) -> Identifier {
+        self.map.data[self.entry].0
+    }
+
+    pub fn get(&self) -> &T {
+        &self.map.data[self.entry].1
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.map.data[self.entry].1
+    }
+
+    pub fn into_mut(self) -> &'a mut T {
+        &mut self.map.data[self.entry].1
+    }
+
+    pub fn insert(&mut self, t: T) -> T {
+        std::mem::replace(self.get_mut(), t)
+    }
+
+    pub fn remove(self) -> T {
+        self.map.remove_entry(self.entry)
+    }
+}
+
+pub struct VacantEntry<'a, T: Invalid, H: KeyHasher> {
+    map: &'a mut Map<T, H>,
+    id: Identifier,
+    key: Option<Box<str>>,
+}
+
+impl<'a, T: Invalid, H: KeyHasher> VacantEntry<'a, T, H> {
+    pub fn id(&self) -> Identifier {
+        self.id
+    }
+
+    pub fn insert(self, t: T) -> &'a mut T {
+        let entry = self
+            .map
+            .push_entry(self.id, self.key, t)
+            .unwrap_or_else(|err| panic!("{}", err));
+        &mut self.map.data[entry].1
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MapOptions<H = Hashing> {
+    pub hasher: H,
+    /// Store the original keys to detect collisions, costs an allocation
+    /// per key.
+    pub keep_keys: bool,
+}
+
+impl<H: Bitwise> Bitwise for MapOptions<H> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.hasher.encode(buffer);
+        self.keep_keys.encode(buffer);
+    }
+
+    fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
+        self.hasher.decode(cursor, buffer)?;
+        self.keep_keys.decode(cursor, buffer)
+    }
+}
+
+/// Hash function a `Map` turns keys into identifiers with.
+pub trait KeyHasher: Clone {
+    fn hash(&self, bytes: &[u8]) -> u64;
+}
+
+#[derive(Bitwise, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Hashing {
+    /// Fast but collides more easily.
+    #[default]
+    Sdbm,
+    Fnv1a,
+}
+
+impl Hashing {
+    pub const fn identify(self, name: &str) -> Identifier {
+        match self {
+            Self::Sdbm => Identifier::new(name),
+            Self::Fnv1a => Identifier::fnv1a(name),
         }
     }
 }
 
+impl KeyHasher for Hashing {
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        match self {
+            Self::Sdbm => sdbm(bytes),
+            Self::Fnv1a => fnv1a(bytes),
+        }
+    }
+}
+
+/// Anything `Map` and `Table` can be looked up with. Precomputed
+/// identifiers (see `ident!`) skip hashing, but also key verification, and
+/// must be hashed the same way as the map.
+pub trait Key {
+    fn identify<H: KeyHasher>(&self, hasher: &H) -> Identifier;
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Key for Identifier {
+    fn identify<H: KeyHasher>(&self, _: &H) -> Identifier {
+        *self
+    }
+}
+
+macro_rules! impl_key_for_str {
+    ($($type:ty),*) => {
+        $(
+            impl Key for $type {
+                fn identify<H: KeyHasher>(&self, hasher: &H) -> Identifier {
+                    Identifier(hasher.hash(self.as_bytes()))
+                }
+
+                fn name(&self) -> Option<&str> {
+                    Some(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_key_for_str!(str, String, Box<str>);
+
+macro_rules! impl_key_for_number {
+    ($($number:ident)*) => {
+        $(
+            impl Key for $number {
+                fn identify<H: KeyHasher>(&self, hasher: &H) -> Identifier {
+                    Identifier(hasher.hash(&self.to_le_bytes()))
+                }
+            }
+        )*
+    };
+}
+
+impl_key_for_number!(
+    u8 u16 u32 u64 u128 usize
+    i8 i16 i32 i64 i128 isize
+);
+
+impl<K: Key + ?Sized> Key for &K {
+    fn identify<H: KeyHasher>(&self, hasher: &H) -> Identifier {
+        (**self).identify(hasher)
+    }
+
+    fn name(&self) -> Option<&str> {
+        (**self).name()
+    }
+}
+
+/// Hashes a string at compile time, `ident!("name")` uses sdbm,
+/// `ident!(Fnv1a, "name")` picks the hashing explicitly.
+#[macro_export]
+macro_rules! ident {
+    ($name:expr) => {
+        $crate::ident!(Sdbm, $name)
+    };
+    ($hashing:ident, $name:expr) => {{
+        const ID: $crate::Identifier = $crate::Hashing::$hashing.identify($name);
+        ID
+    }};
+}
+
+/// Two different keys hashed to the same `Identifier`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collision {
+    pub id: Identifier,
+    pub existing: String,
+    pub key: String,
+}
+
+impl std::fmt::Display for Collision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "identifier collision between '{}' and '{}' ({:#x})",
+            self.existing, self.key, self.id.0
+        )
+    }
+}
+
+impl std::error::Error for Collision {}
+
+/// Store that reuses slots of removed values. Every reuse bumps the slot
+/// generation so handles to removed values are rejected instead of
+/// addressing whatever took their place.
 #[derive(Debug, Clone)]
 pub struct PoolStore<A: Access, T> {
-    data: Vec<Option<T>>,
-    free: Vec<A>,
+    data: Vec<(u32, Option<T>)>,
+    free: Vec<usize>,
+    _pd: PhantomData<A>,
 }
 
 impl<A: Access, T> PoolStore<A, T> {
@@ -239,52 +863,91 @@ impl<A: Access, T> PoolStore<A, T> {
         Self::default()
     }
 
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            _pd: PhantomData,
+        }
+    }
+
     pub fn count(&self) -> usize {
         self.data.len() - self.free.len()
     }
 
     pub fn push(&mut self, t: T) -> A {
-        if let Some(free) = self.free.pop() {
-            self.data[free.index()] = Some(t);
-            free
+        if let Some(index) = self.free.pop() {
+            let (generation, slot) = &mut self.data[index];
+            *slot = Some(t);
+            A::with_generation(index, *generation)
         } else {
             let index = self.data.len();
-            self.data.push(Some(t));
+            self.data.push((0, Some(t)));
             A::new(index)
         }
     }
 
     pub fn remove(&mut self, a: A) -> T {
+        assert!(self.is_valid(a), "removing with stale handle");
         let index = a.index();
-        let t = std::mem::replace(&mut self.data[index], None);
-        self.free.push(a);
+        let (generation, slot) = &mut self.data[index];
+        *generation = generation.wrapping_add(1);
+        let t = slot.take();
+        self.free.push(index);
         t.unwrap()
     }
 
+    /// Same as `remove` but returns `None` for stale or out-of-range handles.
+    pub fn try_remove(&mut self, a: A) -> Option<T> {
+        if self.is_valid(a) {
+            Some(self.remove(a))
+        } else {
+            None
+        }
+    }
+
     pub fn is_valid(&self, a: A) -> bool {
-        self.data[a.index()].is_some()
+        self.get(a).is_some()
+    }
+
+    pub fn contains(&self, a: A) -> bool {
+        self.is_valid(a)
+    }
+
+    pub fn get(&self, a: A) -> Option<&T> {
+        match self.data.get(a.index()) {
+            Some((generation, slot)) if *generation == a.generation() => slot.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, a: A) -> Option<&mut T> {
+        match self.data.get_mut(a.index()) {
+            Some((generation, slot)) if *generation == a.generation() => slot.as_mut(),
+            _ => None,
+        }
     }
 
     pub fn values(&self) -> impl Iterator<Item = &T> {
-        self.data.iter().filter_map(|x| x.as_ref())
+        self.data.iter().filter_map(|(_, x)| x.as_ref())
     }
 
     pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
-        self.data.iter_mut().filter_map(|x| x.as_mut())
+        self.data.iter_mut().filter_map(|(_, x)| x.as_mut())
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (A, &T)> {
         self.data
             .iter()
             .enumerate()
-            .filter_map(|(i, t)| t.as_ref().map(|t| (A::new(i), t)))
+            .filter_map(|(i, (g, t))| t.as_ref().map(|t| (A::with_generation(i, *g), t)))
     }
 
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (A, &mut T)> {
         self.data
             .iter_mut()
             .enumerate()
-            .filter_map(|(i, t)| t.as_mut().map(|t| (A::new(i), t)))
+            .filter_map(|(i, (g, t))| t.as_mut().map(|t| (A::with_generation(i, *g), t)))
     }
 }
 
@@ -293,7 +956,69 @@ impl<A: Access, T> Default for PoolStore<A, T> {
         Self {
             data: Vec::new(),
             free: Vec::new(),
+            _pd: PhantomData,
+        }
+    }
+}
+
+/// Keeps the generations and the free list, so handles and the order in
+/// which slots get reused survive a round-trip.
+impl<A: Access, T: Bitwise + Default> Bitwise for PoolStore<A, T> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.data.len().encode(buffer);
+        for (generation, slot) in &self.data {
+            generation.encode(buffer);
+            slot.is_some().encode(buffer);
+            if let Some(t) = slot {
+                t.encode(buffer);
+            }
+        }
+        self.free.encode(buffer);
+    }
+
+    fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
+        let mut len = 0usize;
+        len.decode(cursor, buffer)?;
+
+        // prevents injected huge allocations
+        if len > buffer.len() - *cursor {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut generation = 0u32;
+            let mut occupied = false;
+            generation.decode(cursor, buffer)?;
+            occupied.decode(cursor, buffer)?;
+            let slot = if occupied {
+                let mut t = T::default();
+                t.decode(cursor, buffer)?;
+                Some(t)
+            } else {
+                None
+            };
+            data.push((generation, slot));
+        }
+
+        let mut free: Vec<usize> = Vec::new();
+        free.decode(cursor, buffer)?;
+
+        // every empty slot has to be free exactly once
+        let mut seen = vec![false; data.len()];
+        for &index in &free {
+            match data.get(index) {
+                Some((_, None)) if !seen[index] => seen[index] = true,
+                _ => return None,
+            }
+        }
+        if data.iter().filter(|(_, slot)| slot.is_none()).count() != free.len() {
+            return None;
         }
+
+        self.data = data;
+        self.free = free;
+        Some(())
     }
 }
 
@@ -301,16 +1026,17 @@ impl<A: Access, T> Index<A> for PoolStore<A, T> {
     type Output = T;
 
     fn index(&self, index: A) -> &Self::Output {
-        self.data[index.index() as usize].as_ref().unwrap()
+        self.get(index).expect("stale handle")
     }
 }
 
 impl<A: Access, T> IndexMut<A> for PoolStore<A, T> {
     fn index_mut(&mut self, index: A) -> &mut Self::Output {
-        self.data[index.index() as usize].as_mut().unwrap()
+        self.get_mut(index).expect("stale handle")
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct Store<A: Access, T> {
     data: Vec<T>,
     _pd: PhantomData<A>,
@@ -337,6 +1063,18 @@ impl<A: Access, T> Store<A, T> {
             .map(|(i, t)| (A::new(i), t))
     }
 
+    pub fn get(&self, a: A) -> Option<&T> {
+        self.data.get(a.index())
+    }
+
+    pub fn get_mut(&mut self, a: A) -> Option<&mut T> {
+        self.data.get_mut(a.index())
+    }
+
+    pub fn contains(&self, a: A) -> bool {
+        a.index() < self.data.len()
+    }
+
     pub fn values(&self) -> impl Iterator<Item = &T> {
         self.data.iter()
     }
@@ -366,6 +1104,19 @@ impl<A: Access, T> Default for Store<A, T> {
     }
 }
 
+impl<A: Access, T: Bitwise + Default> Bitwise for Store<A, T> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.data.encode(buffer);
+    }
+
+    fn decode(&mut self, cursor: &mut usize, buffer: &[u8]) -> Option<()> {
+        let mut data = Vec::new();
+        data.decode(cursor, buffer)?;
+        self.data = data;
+        Some(())
+    }
+}
+
 impl<A: Access, T> Index<A> for Store<A, T> {
     type Output = T;
 
@@ -428,19 +1179,42 @@ impl<T: Invalid> From<Option<T>> for Unverified<T> {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Bitwise, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Identifier(pub u64);
 
 impl Identifier {
-    pub fn new(name: &str) -> Self {
-        // This is sdbm hash.
-        Self(name.as_bytes().iter().fold(0, |acc, &c| {
-            (c as u64)
-                .wrapping_add(acc << 6)
-                .wrapping_add(acc << 16)
-                .wrapping_sub(acc)
-        }))
+    pub const fn new(name: &str) -> Self {
+        Self(sdbm(name.as_bytes()))
+    }
+
+    /// 64-bit FNV-1a, spreads short similar names better than sdbm and does
+    /// not map the empty string to the invalid id.
+    pub const fn fnv1a(name: &str) -> Self {
+        Self(fnv1a(name.as_bytes()))
+    }
+}
+
+const fn sdbm(bytes: &[u8]) -> u64 {
+    let mut acc = 0u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        acc = (bytes[i] as u64)
+            .wrapping_add(acc << 6)
+            .wrapping_add(acc << 16)
+            .wrapping_sub(acc);
+        i += 1;
     }
+    acc
+}
+
+const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut acc = 0xcbf29ce484222325u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        acc = (acc ^ bytes[i] as u64).wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    acc
 }
 
 impl Invalid for Identifier {
@@ -460,30 +1234,41 @@ pub trait Invalid {
 }
 
 pub trait Access: Clone + Copy + Eq + PartialEq {
-    fn new(index: usize) -> Self;
+    fn new(index: usize) -> Self {
+        Self::with_generation(index, 0)
+    }
+
+    /// Handles into stores that never reuse slots keep generation 0.
+    fn with_generation(index: usize, generation: u32) -> Self;
     fn index(&self) -> usize;
+    fn generation(&self) -> u32;
 }
 
 #[macro_export]
 macro_rules! create_access {
     ($($name:ident)*) => {
         $(
-            #[derive(Bitwise, Copy, Clone, Debug, PartialEq, Eq)]
-            pub struct $name(pub u32);
+            /// Index and generation of the slot.
+            #[derive(Bitwise, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+            pub struct $name(pub u32, pub u32);
 
             impl $crate::Access for $name {
-                fn new(index: usize) -> Self {
-                    $name(index as u32)
+                fn with_generation(index: usize, generation: u32) -> Self {
+                    $name(index as u32, generation)
                 }
 
                 fn index(&self) -> usize {
                     self.0 as usize
                 }
+
+                fn generation(&self) -> u32 {
+                    self.1
+                }
             }
 
             impl $crate::Invalid for $name {
                 fn invalid() -> Self {
-                    $name(u32::MAX)
+                    $name(u32::MAX, 0)
                 }
 
                 fn is_invalid(&self) -> bool {
@@ -507,7 +1292,7 @@ mod test {
     use rand::{Rng, SeedableRng};
     use rand_chacha::ChaCha8Rng;
 
-    use super::Invalid;
+    use super::*;
 
     impl Invalid for u32 {
         fn invalid() -> Self {
@@ -519,68 +1304,299 @@ mod test {
         }
     }
 
+    crate::create_access!(Id);
+
     #[test]
-    fn fuzz_map() {
-        use super::*;
+    fn stale_handles() {
+        let mut store = PoolStore::<Id, u32>::new();
+        let a = store.push(1);
+        let b = store.push(2);
+        assert_eq!(store.remove(a), 1);
+
+        let c = store.push(3);
+        assert_eq!(c.index(), a.index());
+        assert!(!store.is_valid(a));
+        assert_eq!(store.get(a), None);
+        assert_eq!(store.get_mut(a), None);
+        assert_eq!(store.get(c), Some(&3));
+        assert_eq!(store[b], 2);
+        assert_eq!(
+            store.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![c, b]
+        );
+        assert_eq!(store.count(), 2);
+
+        assert_eq!(store.try_remove(a), None);
+        assert_eq!(store.try_remove(Id(100, 0)), None);
+        assert!(!store.contains(Id(u32::MAX, 0)));
+        assert_eq!(store.try_remove(b), Some(2));
+        assert!(!store.contains(b));
+    }
 
-        let mut data = vec![];
-        let mut std_map = HashMap::new();
-        let mut map = Map::new();
-        let mut rng = ChaCha8Rng::seed_from_u64(2); //rand::thread_rng();
+    #[test]
+    fn out_of_range() {
+        let mut store = Store::<Id, u32>::new();
+        let a = store.push(1);
+        assert_eq!(store.get(a), Some(&1));
+        assert_eq!(store.get(Id(1, 0)), None);
+        assert!(!store.contains(Id(1, 0)));
+
+        let mut table = Table::<Id, Id>::new();
+        table.insert("a", a);
+        assert!(table.contains("a"));
+        assert!(!table.contains("b"));
+        assert_eq!(table.get_mut("b"), None);
+        assert_eq!(table.get_mut("a").copied(), Some(a));
+    }
 
-        data.extend((0..1000000).map(|i| {
-            let len = 10; //rng.gen_range(1..10);
-            let mut string = String::with_capacity(len);
-            for _ in 0..len {
-                string.push(rng.gen_range('a'..'z'));
-            }
-            (string, i)
-        }));
+    #[test]
+    fn table_removal() {
+        let mut table = Table::<Id, u32>::new();
+        let a = table.insert("a", 1);
+        let b = table.insert("b", 2);
+        assert_eq!(table.insert("a", 3), a);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.replace("a", 4), Some(3));
+        assert_eq!(table.replace("c", 5), None);
+        assert_eq!(table[a], 4);
+
+        assert_eq!(table.name_of(b), Some(Identifier::new("b")));
+        assert_eq!(table.remove("b"), Some(2));
+        assert_eq!(table.remove("b"), None);
+        assert_eq!(table.name_of(b), None);
+        assert_eq!(table.try_remove(b), None);
+        assert!(!table.is_valid(b));
+
+        // the freed slot is reused under a new generation
+        let d = table.insert("d", 6);
+        assert_eq!(d.index(), b.index());
+        assert_ne!(d, b);
+        assert_eq!(table.access("d"), Some(d));
+        assert_eq!(table.try_remove(a), Some(4));
+
+        let mut named = table
+            .iter_named()
+            .map(|(id, key, &value)| (id, key, value))
+            .collect::<Vec<_>>();
+        named.sort_by_key(|&(_, _, value)| value);
+        assert_eq!(
+            named,
+            vec![
+                (Identifier::new("c"), table.access("c").unwrap(), 5),
+                (Identifier::new("d"), d, 6),
+            ]
+        );
+    }
 
-        bench("insert my", || {
-            for (key, value) in data.iter() {
-                map.insert(&key.clone(), *value);
-            }
-        });
+    #[test]
+    fn collisions() {
+        // sdbm ignores leading zero bytes
+        assert_eq!(Identifier::new("ba"), Identifier::new("\0ba"));
+        assert_ne!(Identifier::fnv1a("ba"), Identifier::fnv1a("\0ba"));
+        assert!(!Identifier::fnv1a("").is_invalid());
+
+        let mut map = Map::<u32>::new();
+        map.insert("ba", 1);
+        assert_eq!(map.get("\0ba"), Some(&1));
+
+        let options = MapOptions {
+            keep_keys: true,
+            ..MapOptions::default()
+        };
+        let mut table = Table::<Id, u32>::with_options(0, options);
+        let a = table.insert("ba", 1);
+        let err = table.try_insert("\0ba", 2).unwrap_err();
+        assert_eq!((&*err.existing, &*err.key), ("ba", "\0ba"));
+        assert_eq!(table.get("\0ba"), None);
+        assert_eq!(table.remove("\0ba"), None);
+        assert_eq!(table.get("ba"), Some(&1));
+        assert_eq!(table.key_of(a), Some("ba"));
+
+        for i in 0..100 {
+            table.insert(i.to_string(), i);
+        }
+        assert_eq!(table.key_of(a), Some("ba"));
+        assert_eq!(table.get("42"), Some(&42));
 
-        bench("insert std", || {
-            for (key, value) in data.iter() {
-                std_map.insert(key.clone(), *value);
-            }
-        });
+        let options = MapOptions {
+            hasher: Hashing::Fnv1a,
+            keep_keys: true,
+        };
+        let mut table = Table::<Id, u32>::with_options(0, options);
+        table.insert("ba", 1);
+        table.insert("\0ba", 2);
+        assert_eq!(table.get("ba"), Some(&1));
+        assert_eq!(table.get("\0ba"), Some(&2));
+    }
 
-        bench("lookup my", || {
-            for (key, _) in data.iter() {
-                map.get(&key);
-            }
-        });
+    #[test]
+    fn precomputed() {
+        const GRASS: Identifier = ident!("grass");
+        let mut table = Table::<Id, u32>::new();
+        let grass = table.insert("grass", 1);
+        assert_eq!(table.get(GRASS), Some(&1));
+        assert_eq!(table.access(ident!("grass")), Some(grass));
+        assert_eq!(table.insert(GRASS, 2), grass);
+        assert_eq!(table.replace("grass".to_string(), 3), Some(2));
+        assert_eq!(table.remove(GRASS), Some(3));
+        assert!(!table.contains("grass"));
+
+        assert_eq!(ident!(Fnv1a, ""), Identifier::fnv1a(""));
+    }
 
-        bench("lookup std", || {
-            for (key, _) in data.iter() {
-                std_map.get(key);
-            }
-        });
+    #[test]
+    fn bitwise() {
+        let options = MapOptions {
+            hasher: Hashing::Fnv1a,
+            keep_keys: true,
+        };
+        let mut table = Table::<Id, u32>::with_options(0, options);
+        let a = table.insert("a", 1);
+        let b = table.insert("b", 2);
+        table.insert("c", 3);
+        table.remove("b");
+
+        let mut buffer = vec![];
+        table.encode(&mut buffer);
+        let mut decoded = Table::<Id, u32>::new();
+        decoded.decode(&mut 0, &buffer).unwrap();
+        assert_eq!(decoded.lookup.options(), &options);
+        assert_eq!(decoded.get("a"), Some(&1));
+        assert_eq!(decoded.access("c"), table.access("c"));
+        assert_eq!(decoded.key_of(a), Some("a"));
+        assert!(!decoded.is_valid(b));
+        // the free slot is reused the same way
+        assert_eq!(decoded.insert("d", 4), table.insert("d", 4));
+
+        // truncated or inconsistent data is rejected
+        for len in 0..buffer.len() {
+            assert!(Table::<Id, u32>::new()
+                .decode(&mut 0, &buffer[..len])
+                .is_none());
+        }
+        let mut pool = PoolStore::<Id, u32>::new();
+        pool.push(1);
+        let mut buffer = vec![];
+        pool.encode(&mut buffer);
+        // claim the occupied slot is free
+        buffer.truncate(buffer.len() - std::mem::size_of::<usize>());
+        vec![0usize].encode(&mut buffer);
+        assert!(PoolStore::<Id, u32>::new()
+            .decode(&mut 0, &buffer)
+            .is_none());
+
+        let mut store = Store::<Id, u32>::new();
+        store.push(5);
+        let mut buffer = vec![];
+        store.encode(&mut buffer);
+        let mut decoded = Store::<Id, u32>::new();
+        decoded.decode(&mut 0, &buffer).unwrap();
+        assert_eq!(decoded.get(Id(0, 0)), Some(&5));
+    }
 
-        bench("remove my", || {
-            for (key, _) in data.iter() {
-                map.remove(&key);
+    #[test]
+    fn fuzz_table() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut table = Table::<Id, u32>::new();
+        let mut reference = HashMap::new();
+
+        for _ in 0..10000 {
+            let key = rng.gen_range(0..200u32).to_string();
+            let value = rng.gen::<u32>();
+            if rng.gen_bool(0.4) {
+                assert_eq!(table.remove(&key), reference.remove(&key));
+            } else {
+                assert_eq!(table.replace(&key, value), reference.insert(key, value));
             }
-        });
+        }
+
+        assert_eq!(table.len(), reference.len());
+        for (id, key, value) in table.iter_named() {
+            assert_eq!(table.access(id), Some(key));
+            assert_eq!(table.get_by_id(id), Some(value));
+        }
+        for (key, value) in &reference {
+            assert_eq!(table.get(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn fuzz_map() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let mut std_map = HashMap::new();
+        let mut map = Map::new();
 
-        bench("remove std", || {
-            for (key, _) in data.iter() {
-                std_map.remove(key);
+        for _ in 0..100000 {
+            let key = rng.gen_range(0..2000u32).to_string();
+            let value = rng.gen::<u32>() >> 1;
+            match rng.gen_range(0..5) {
+                0 => assert_eq!(map.remove(&key), std_map.remove(&key)),
+                1 => assert_eq!(map.get(&key), std_map.get(&key)),
+                2 => {
+                    *map.entry(&key).or_default() += 1;
+                    *std_map.entry(key).or_default() += 1;
+                }
+                _ => assert_eq!(map.insert(&key, value), std_map.insert(key, value)),
             }
-        });
+            assert_eq!(map.len(), std_map.len());
+        }
+
+        map.retain(|_, value| *value % 2 == 0);
+        std_map.retain(|_, value| *value % 2 == 0);
+        map.shrink_to_fit();
+        assert_eq!(map.len(), std_map.len());
+        for (key, value) in &std_map {
+            assert_eq!(map.get(key), Some(value));
+        }
 
-        map.clear();
-        std_map.clear();
+        let capacity = map.data.capacity();
+        let mut drained = map.drain().map(|(_, value)| value).collect::<Vec<_>>();
+        let mut expected = std_map.into_values().collect::<Vec<_>>();
+        drained.sort();
+        expected.sort();
+        assert_eq!(drained, expected);
+        assert!(map.is_empty());
+        assert_eq!(map.iter().count(), 0);
+        assert_eq!(map.data.capacity(), capacity);
     }
 
-    pub fn bench(name: &str, f: impl FnOnce()) {
-        let start = std::time::Instant::now();
-        f();
-        let end = start.elapsed();
-        println!("[{}] {}s", name, end.as_secs_f64());
+    #[test]
+    fn entries_and_hashers() {
+        #[derive(Clone, Default)]
+        struct Xor;
+
+        impl KeyHasher for Xor {
+            fn hash(&self, bytes: &[u8]) -> u64 {
+                bytes
+                    .iter()
+                    .fold(0, |acc, &b| acc.rotate_left(8) ^ b as u64)
+            }
+        }
+
+        let mut map = Map::<u32, Xor>::with_hasher(0, Xor);
+        assert_eq!(map.identify("ab"), Identifier(0x6162));
+        *map.entry(7u64).or_insert(1) += 1;
+        map.entry(7u64)
+            .and_modify(|value| *value *= 10)
+            .or_insert(0);
+        assert_eq!(map.get(7u64), Some(&20));
+
+        match map.entry("x") {
+            Entry::Vacant(entry) => {
+                assert_eq!(entry.id(), Identifier(b'x' as u64));
+                entry.insert(3);
+            }
+            Entry::Occupied(_) => unreachable!(),
+        }
+        match map.entry("x") {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(4), 3);
+                assert_eq!(entry.remove(), 4);
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert!(!map.contains("x"));
+        *map.get_mut(7u64).unwrap() = 1;
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(map.identify(7u64), &1)]);
     }
 }
@@ -0,0 +1,269 @@
+use std::ops::{Index, IndexMut};
+
+use crate::Access;
+
+/// Component storage keyed by an entity id. Values are packed densely so
+/// iteration is as fast as over a `Vec`, the sparse part maps id index to
+/// position in the dense part. Handles carry their generation, an entry
+/// inserted with an older generation is not returned for a newer one.
+#[derive(Debug, Clone)]
+pub struct SparseSet<A: Access, T> {
+    sparse: Vec<u32>,
+    ids: Vec<A>,
+    values: Vec<T>,
+}
+
+impl<A: Access, T> SparseSet<A, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            sparse: Vec::with_capacity(capacity),
+            ids: Vec::with_capacity(capacity),
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Inserts the value, replacing whatever was stored under the same index
+    /// (even from an older generation).
+    pub fn insert(&mut self, a: A, t: T) -> Option<T> {
+        if let Some(position) = self.position_of_index(a.index()) {
+            self.ids[position] = a;
+            return Some(std::mem::replace(&mut self.values[position], t));
+        }
+
+        if self.sparse.len() <= a.index() {
+            self.sparse.resize(a.index() + 1, u32::MAX);
+        }
+        self.sparse[a.index()] = self.values.len() as u32;
+        self.ids.push(a);
+        self.values.push(t);
+        None
+    }
+
+    pub fn remove(&mut self, a: A) -> Option<T> {
+        let position = self.position(a)?;
+        self.sparse[a.index()] = u32::MAX;
+        self.ids.swap_remove(position);
+        if let Some(moved) = self.ids.get(position) {
+            self.sparse[moved.index()] = position as u32;
+        }
+        Some(self.values.swap_remove(position))
+    }
+
+    pub fn get(&self, a: A) -> Option<&T> {
+        self.position(a).map(|position| &self.values[position])
+    }
+
+    pub fn get_mut(&mut self, a: A) -> Option<&mut T> {
+        self.position(a).map(|position| &mut self.values[position])
+    }
+
+    pub fn contains(&self, a: A) -> bool {
+        self.position(a).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.sparse.clear();
+        self.ids.clear();
+        self.values.clear();
+    }
+
+    pub fn ids(&self) -> &[A] {
+        &self.ids
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [T] {
+        &mut self.values
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (A, &T)> {
+        self.ids.iter().copied().zip(self.values.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A, &mut T)> {
+        self.ids.iter().copied().zip(self.values.iter_mut())
+    }
+
+    /// Entries present in both sets, walks the smaller one.
+    pub fn join<'a, U>(
+        &'a self,
+        other: &'a SparseSet<A, U>,
+    ) -> impl Iterator<Item = (A, &'a T, &'a U)> + 'a {
+        let smaller = if self.len() <= other.len() {
+            self.ids()
+        } else {
+            other.ids()
+        };
+        smaller
+            .iter()
+            .filter_map(move |&a| Some((a, self.get(a)?, other.get(a)?)))
+    }
+
+    /// Mutable join, walks `self` since only its values can be borrowed
+    /// mutably while iterating.
+    pub fn join_mut<'a, U>(
+        &'a mut self,
+        other: &'a SparseSet<A, U>,
+    ) -> impl Iterator<Item = (A, &'a mut T, &'a U)> + 'a {
+        self.iter_mut()
+            .filter_map(move |(a, t)| Some((a, t, other.get(a)?)))
+    }
+
+    fn position(&self, a: A) -> Option<usize> {
+        self.position_of_index(a.index())
+            .filter(|&position| self.ids[position] == a)
+    }
+
+    fn position_of_index(&self, index: usize) -> Option<usize> {
+        match self.sparse.get(index) {
+            Some(&position) if position != u32::MAX => Some(position as usize),
+            _ => None,
+        }
+    }
+}
+
+impl<A: Access, T> Default for SparseSet<A, T> {
+    fn default() -> Self {
+        Self {
+            sparse: Vec::new(),
+            ids: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+}
+
+impl<A: Access, T> Index<A> for SparseSet<A, T> {
+    type Output = T;
+
+    fn index(&self, index: A) -> &Self::Output {
+        self.get(index).expect("entity does not have the component")
+    }
+}
+
+impl<A: Access, T> IndexMut<A> for SparseSet<A, T> {
+    fn index_mut(&mut self, index: A) -> &mut Self::Output {
+        self.get_mut(index)
+            .expect("entity does not have the component")
+    }
+}
+
+/// Joins any number of sparse sets, yielding the id and one value per set
+/// for ids present in all of them. Prefix the first set with `mut` to get
+/// its values mutably.
+///
+/// `join!(mut positions, velocities).for_each(|(_, p, v)| *p += *v)`
+#[macro_export]
+macro_rules! join {
+    (mut $first:expr $(, $rest:expr)+ $(,)?) => {
+        $first
+            .iter_mut()
+            .filter_map(|(id, first)| Some((id, first, $($rest.get(id)?),+)))
+    };
+    ($first:expr $(, $rest:expr)+ $(,)?) => {
+        $first
+            .iter()
+            .filter_map(|(id, first)| Some((id, first, $($rest.get(id)?),+)))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    create_access!(Entity);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Position(i32);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Velocity(i32);
+
+    #[test]
+    fn insert_remove() {
+        let mut entities = PoolStore::<Entity, ()>::new();
+        let ids = (0..4).map(|_| entities.push(())).collect::<Vec<_>>();
+
+        let mut positions = SparseSet::new();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(positions.insert(id, Position(i as i32)), None);
+        }
+        assert_eq!(positions.remove(ids[0]), Some(Position(0)));
+        assert_eq!(positions.remove(ids[0]), None);
+        assert_eq!(positions.len(), 3);
+        // the last value took the freed spot
+        assert_eq!(positions.ids()[0], ids[3]);
+        assert_eq!(positions[ids[3]], Position(3));
+
+        // a reused id does not see the component of the removed entity
+        entities.remove(ids[1]);
+        let reused = entities.push(());
+        assert_eq!(reused.index(), ids[1].index());
+        assert!(!positions.contains(reused));
+        assert_eq!(positions.insert(reused, Position(9)), Some(Position(1)));
+        assert_eq!(positions.get(ids[1]), None);
+        assert_eq!(positions.get(reused), Some(&Position(9)));
+    }
+
+    #[test]
+    fn joins() {
+        let ids = (0..6).map(|i| Entity(i, 0)).collect::<Vec<_>>();
+        let mut positions = SparseSet::new();
+        let mut velocities = SparseSet::new();
+        let mut names = SparseSet::new();
+        for &id in &ids {
+            positions.insert(id, Position(id.0 as i32));
+            if id.0 % 2 == 0 {
+                velocities.insert(id, Velocity(10));
+            }
+            if id.0 % 3 == 0 {
+                names.insert(id, id.0.to_string());
+            }
+        }
+
+        for (_, position, velocity) in positions.join_mut(&velocities) {
+            position.0 += velocity.0;
+        }
+        assert_eq!(
+            positions.values(),
+            &[0, 1, 2, 3, 4, 5].map(|i| Position(i + 10 * (i % 2 == 0) as i32))
+        );
+
+        let joined = velocities
+            .join(&positions)
+            .map(|(id, _, position)| (id, *position))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            joined,
+            vec![
+                (ids[0], Position(10)),
+                (ids[2], Position(12)),
+                (ids[4], Position(14)),
+            ]
+        );
+
+        let joined = join!(mut positions, velocities, names)
+            .map(|(id, position, _, name)| {
+                position.0 = 0;
+                (id, name.clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(joined, vec![(ids[0], "0".to_string())]);
+        assert_eq!(positions[ids[0]], Position(0));
+        assert_eq!(join!(names, velocities).count(), 1);
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    ops::{Deref, Index, IndexMut},
+    vec::Drain,
+};
+
+use crate::{Access, PoolStore, Store};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<A> {
+    Inserted(A),
+    Removed(A),
+    Mutated(A),
+}
+
+/// Ordered list of changes since the last drain. Mutations are recorded
+/// once per entry until drained, and not at all for entries inserted since
+/// then, as the insertion already covers them.
+#[derive(Debug, Clone)]
+pub struct ChangeLog<A: Access> {
+    log: Vec<Change<A>>,
+    dirty: Vec<bool>,
+}
+
+impl<A: Access> ChangeLog<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inserted(&mut self, a: A) {
+        self.mark(a);
+        self.log.push(Change::Inserted(a));
+    }
+
+    pub fn removed(&mut self, a: A) {
+        self.mark(a);
+        self.log.push(Change::Removed(a));
+    }
+
+    pub fn mutated(&mut self, a: A) {
+        if !self.mark(a) {
+            self.log.push(Change::Mutated(a));
+        }
+    }
+
+    pub fn changes(&self) -> &[Change<A>] {
+        &self.log
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    pub fn drain(&mut self) -> Drain<'_, Change<A>> {
+        for change in &self.log {
+            let (Change::Inserted(a) | Change::Removed(a) | Change::Mutated(a)) = change;
+            self.dirty[a.index()] = false;
+        }
+        self.log.drain(..)
+    }
+
+    /// Returns whether the entry was already marked.
+    fn mark(&mut self, a: A) -> bool {
+        if self.dirty.len() <= a.index() {
+            self.dirty.resize(a.index() + 1, false);
+        }
+        std::mem::replace(&mut self.dirty[a.index()], true)
+    }
+}
+
+impl<A: Access> Default for ChangeLog<A> {
+    fn default() -> Self {
+        Self {
+            log: Vec::new(),
+            dirty: Vec::new(),
+        }
+    }
+}
+
+/// `PoolStore` that records every insertion, removal and mutable access.
+/// Read access goes through `Deref`, anything mutable through this type.
+#[derive(Debug, Clone)]
+pub struct TrackedPoolStore<A: Access, T> {
+    data: PoolStore<A, T>,
+    changes: ChangeLog<A>,
+}
+
+impl<A: Access, T> TrackedPoolStore<A, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, t: T) -> A {
+        let a = self.data.push(t);
+        self.changes.inserted(a);
+        a
+    }
+
+    pub fn remove(&mut self, a: A) -> T {
+        let t = self.data.remove(a);
+        self.changes.removed(a);
+        t
+    }
+
+    pub fn try_remove(&mut self, a: A) -> Option<T> {
+        let t = self.data.try_remove(a)?;
+        self.changes.removed(a);
+        Some(t)
+    }
+
+    pub fn get_mut(&mut self, a: A) -> Option<&mut T> {
+        let t = self.data.get_mut(a)?;
+        self.changes.mutated(a);
+        Some(t)
+    }
+
+    /// Marks every entry as mutated.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A, &mut T)> {
+        let changes = &mut self.changes;
+        self.data
+            .iter_mut()
+            .inspect(move |(a, _)| changes.mutated(*a))
+    }
+
+    pub fn changes(&self) -> &[Change<A>] {
+        self.changes.changes()
+    }
+
+    pub fn drain_changes(&mut self) -> Drain<'_, Change<A>> {
+        self.changes.drain()
+    }
+
+    pub fn into_inner(self) -> PoolStore<A, T> {
+        self.data
+    }
+}
+
+impl<A: Access, T> Default for TrackedPoolStore<A, T> {
+    fn default() -> Self {
+        Self {
+            data: PoolStore::default(),
+            changes: ChangeLog::default(),
+        }
+    }
+}
+
+impl<A: Access, T> From<PoolStore<A, T>> for TrackedPoolStore<A, T> {
+    fn from(data: PoolStore<A, T>) -> Self {
+        Self {
+            data,
+            changes: ChangeLog::default(),
+        }
+    }
+}
+
+impl<A: Access, T> Deref for TrackedPoolStore<A, T> {
+    type Target = PoolStore<A, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl<A: Access, T> Index<A> for TrackedPoolStore<A, T> {
+    type Output = T;
+
+    fn index(&self, index: A) -> &Self::Output {
+        &self.data[index]
+    }
+}
+
+impl<A: Access, T> IndexMut<A> for TrackedPoolStore<A, T> {
+    fn index_mut(&mut self, index: A) -> &mut Self::Output {
+        self.get_mut(index).expect("stale handle")
+    }
+}
+
+/// `Store` that records every insertion and mutable access.
+#[derive(Debug, Clone)]
+pub struct TrackedStore<A: Access, T> {
+    data: Store<A, T>,
+    changes: ChangeLog<A>,
+}
+
+impl<A: Access, T> TrackedStore<A, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, t: T) -> A {
+        let a = self.data.push(t);
+        self.changes.inserted(a);
+        a
+    }
+
+    pub fn get_mut(&mut self, a: A) -> Option<&mut T> {
+        let t = self.data.get_mut(a)?;
+        self.changes.mutated(a);
+        Some(t)
+    }
+
+    /// Marks every entry as mutated.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A, &mut T)> {
+        let changes = &mut self.changes;
+        self.data
+            .iter_mut()
+            .inspect(move |(a, _)| changes.mutated(*a))
+    }
+
+    pub fn changes(&self) -> &[Change<A>] {
+        self.changes.changes()
+    }
+
+    pub fn drain_changes(&mut self) -> Drain<'_, Change<A>> {
+        self.changes.drain()
+    }
+
+    pub fn into_inner(self) -> Store<A, T> {
+        self.data
+    }
+}
+
+impl<A: Access, T> Default for TrackedStore<A, T> {
+    fn default() -> Self {
+        Self {
+            data: Store::default(),
+            changes: ChangeLog::default(),
+        }
+    }
+}
+
+impl<A: Access, T> From<Store<A, T>> for TrackedStore<A, T> {
+    fn from(data: Store<A, T>) -> Self {
+        Self {
+            data,
+            changes: ChangeLog::default(),
+        }
+    }
+}
+
+impl<A: Access, T> Deref for TrackedStore<A, T> {
+    type Target = Store<A, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl<A: Access, T> Index<A> for TrackedStore<A, T> {
+    type Output = T;
+
+    fn index(&self, index: A) -> &Self::Output {
+        &self.data[index]
+    }
+}
+
+impl<A: Access, T> IndexMut<A> for TrackedStore<A, T> {
+    fn index_mut(&mut self, index: A) -> &mut Self::Output {
+        self.get_mut(index).expect("index out of bounds")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Change::*;
+    use crate::*;
+
+    create_access!(Id);
+
+    #[test]
+    fn pool_store_changes() {
+        let mut store = TrackedPoolStore::<Id, u32>::new();
+        let a = store.push(1);
+        let b = store.push(2);
+        store[a] += 1;
+        assert_eq!(
+            store.drain_changes().collect::<Vec<_>>(),
+            [Inserted(a), Inserted(b)]
+        );
+
+        store[a] += 1;
+        *store.get_mut(a).unwrap() += 1;
+        assert_eq!(store.get_mut(Id(a.0, a.1 + 1)), None);
+        store.remove(b);
+        let c = store.push(3);
+        assert_eq!(store.try_remove(b), None);
+        assert_eq!(store.changes(), [Mutated(a), Removed(b), Inserted(c)]);
+        assert_eq!(store.drain_changes().count(), 3);
+
+        store.iter_mut().for_each(|(_, value)| *value = 0);
+        assert_eq!(
+            store.drain_changes().collect::<Vec<_>>(),
+            [Mutated(a), Mutated(c)]
+        );
+        assert_eq!(store.values().sum::<u32>(), 0);
+        assert!(store.changes().is_empty());
+    }
+
+    #[test]
+    fn store_changes() {
+        let mut store = TrackedStore::<Id, u32>::new();
+        let a = store.push(1);
+        assert_eq!(store.drain_changes().collect::<Vec<_>>(), [Inserted(a)]);
+        store[a] = 4;
+        store[a] = 5;
+        assert_eq!(store.get_mut(Id(1, 0)), None);
+        assert_eq!(store.drain_changes().collect::<Vec<_>>(), [Mutated(a)]);
+        assert_eq!(store.into_inner()[a], 5);
+    }
+}
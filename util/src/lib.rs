@@ -12,8 +12,8 @@ pub mod sync;
 
 pub mod prelude {
     pub use crate::{
-        meta_data::Deserialize, ImageExtension, RaylibDrawHandleExtension, RectangleExtension,
-        Vector2Extension,
+        meta_data::{Deserialize, Serialize},
+        ImageExtension, RaylibDrawHandleExtension, RectangleExtension, Vector2Extension,
     };
     pub use bitwise::*;
     pub use derive::Meta;
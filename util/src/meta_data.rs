@@ -1,28 +1,155 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::Hash,
+};
+
+use raylib::prelude::{Color, Rectangle, Vector2};
+use store::{Access, Invalid, Table};
 
 pub use minimal_yaml::{parse, Entry, Yaml};
 
+/// Implements [`Deserialize`] and [`Serialize`] for handles resolved by name
+/// through state implementing [`Intern`].
 #[macro_export]
 macro_rules! impl_deserialize_state {
-    ($id:ty) => {
-        impl Deserialize for $id {
-            fn deserialize<V, T: State<V>>(state: &mut T) -> Result<Self, String> {
-                Ok(state.parse(str::deserialize(state)?)?)
+    ($($id:ty),* $(,)?) => {
+        $(
+            impl<T: $crate::meta_data::Intern<$id>> $crate::meta_data::Deserialize<T> for $id {
+                fn deserialize_into(
+                    &mut self,
+                    state: &mut T,
+                    node: $crate::meta_data::Yaml,
+                ) -> Result<(), $crate::meta_data::MetaError> {
+                    *self = $crate::meta_data::intern(state, node, stringify!($id))?;
+                    Ok(())
+                }
             }
-        }
+
+            impl<T: $crate::meta_data::Intern<$id>> $crate::meta_data::Serialize<T> for $id {
+                fn serialize(
+                    &self,
+                    state: &T,
+                ) -> Result<$crate::meta_data::Node, $crate::meta_data::MetaError> {
+                    match state.name_of(self) {
+                        Some(name) => $crate::meta_data::Node::scalar(name),
+                        None => Err($crate::meta_data::MetaError {
+                            message: format!("{} {:?} has no name", stringify!($id), self),
+                            ..Default::default()
+                        }),
+                    }
+                }
+            }
+        )*
     };
 }
 
+/// Deserialization state that resolves names into handles of type `I`.
+pub trait Intern<I> {
+    /// Returns the handle for `name`, state may create it on demand.
+    fn resolve(&mut self, name: &str) -> Option<I>;
+
+    fn name_of(&self, id: &I) -> Option<&str>;
+
+    /// Known names, used to suggest a fix when name is not found.
+    fn names(&self) -> Vec<&str> {
+        vec![]
+    }
+}
+
+/// Resolves names of existing entries, writing handles back needs the table
+/// to keep keys, see [`store::MapOptions::keep_keys`].
+impl<A: Access + Invalid, T> Intern<A> for Table<A, T> {
+    fn resolve(&mut self, name: &str) -> Option<A> {
+        self.access(name)
+    }
+
+    fn name_of(&self, id: &A) -> Option<&str> {
+        self.key_of(*id)
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.iter_named()
+            .filter_map(|(_, a, _)| self.key_of(a))
+            .collect()
+    }
+}
+
+pub fn intern<T: Intern<I>, I>(state: &mut T, node: Yaml, kind: &str) -> Result<I, MetaError> {
+    match node {
+        Yaml::Scalar(str) => {
+            let name = unquote(str);
+            state.resolve(name).ok_or_else(|| {
+                let message = match suggest(name, state.names()) {
+                    Some(similar) => {
+                        format!("{} '{}' not found, did you mean '{}'?", kind, name, similar)
+                    }
+                    None => format!("{} '{}' not found", kind, name),
+                };
+                MetaError::new(&node, message)
+            })
+        }
+        _ => Err(MetaError::expected(&format!("{} name", kind), &node)),
+    }
+}
+
+/// Picks the closest name by edit distance if it is close enough to be a typo.
+fn suggest<'a>(name: &str, names: Vec<&'a str>) -> Option<&'a str> {
+    let tolerance = (name.chars().count() / 3).max(1);
+    names
+        .into_iter()
+        .map(|other| (edit_distance(name, other), other))
+        .filter(|&(distance, _)| distance <= tolerance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, other)| other)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
 pub trait Deserialize<T>: Sized + Default {
-    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), String>;
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError>;
 
-    fn deserialize(state: &mut T, node: Yaml) -> Result<Self, String> {
+    fn deserialize(state: &mut T, node: Yaml) -> Result<Self, MetaError> {
         let mut result = Self::default();
         result.deserialize_into(state, node)?;
         Ok(result)
     }
 }
 
+/// Parses `source` and deserializes it into `target`. Errors are located
+/// inside `source` and tagged with `file`.
+pub fn load_into<S, T: Deserialize<S>>(
+    file: &str,
+    source: &str,
+    state: &mut S,
+    target: &mut T,
+) -> Result<(), MetaError> {
+    Document::from_source(file, source)?.deserialize_into(state, target)
+}
+
+pub trait Serialize<T> {
+    /// Fails when the value cannot be written so that it loads back.
+    fn serialize(&self, state: &T) -> Result<Node, MetaError>;
+}
+
+/// Serializes `value` into yaml text that [`load_into`] reads back.
+pub fn dump<S, T: Serialize<S>>(state: &S, value: &T) -> Result<String, MetaError> {
+    value.serialize(state)?.to_yaml()
+}
+
 pub fn extract_field<'a>(fields: &mut Vec<Entry<'a>>, name: &str) -> Option<Yaml<'a>> {
     fields
         .iter()
@@ -30,63 +157,1632 @@ pub fn extract_field<'a>(fields: &mut Vec<Entry<'a>>, name: &str) -> Option<Yaml
         .map(|index| fields.remove(index).value)
 }
 
+/// Yaml source with anchors, merge keys (`<<`), `include:` and `extends:`
+/// resolved before deserialization.
+///
+/// `include: path` (or a sequence of paths) at the top level pulls in the
+/// top level entries of other files, relative to the including file, and
+/// entries of the including file win. An entry containing `extends: name`
+/// (or a sequence of names) is deep merged over the named top level
+/// entries, nested mappings are merged and everything else is overridden.
+pub struct Document {
+    sources: Vec<Source>,
+}
+
+struct Source {
+    file: String,
+    /// Identity used for cycle detection, canonical path if file exists.
+    id: std::path::PathBuf,
+    /// Source with anchors and alias markers blanked out.
+    text: String,
+    anchors: Names,
+    aliases: Names,
+    includes: Vec<usize>,
+}
+
+impl Document {
+    pub fn load(path: &str) -> Result<Self, MetaError> {
+        let source = std::fs::read_to_string(path).map_err(|err| MetaError {
+            file: path.to_string(),
+            message: format!("failed to read: {}", err),
+            ..MetaError::default()
+        })?;
+        Self::from_source(path, &source)
+    }
+
+    /// Included files are looked up relative to `file`.
+    pub fn from_source(file: &str, source: &str) -> Result<Self, MetaError> {
+        let mut document = Self { sources: vec![] };
+        match document.add_source(file.to_string(), source, &mut vec![]) {
+            Ok(_) => Ok(document),
+            Err(err) => Err(document.locate(err)),
+        }
+    }
+
+    fn add_source(
+        &mut self,
+        file: String,
+        source: &str,
+        chain: &mut Vec<std::path::PathBuf>,
+    ) -> Result<usize, MetaError> {
+        let (text, anchors, aliases) = mask_anchors(source);
+        let id = std::fs::canonicalize(&file).unwrap_or_else(|_| file.clone().into());
+        let index = self.sources.len();
+        self.sources.push(Source {
+            file,
+            id,
+            text,
+            anchors,
+            aliases,
+            includes: vec![],
+        });
+
+        chain.push(self.sources[index].id.clone());
+        for (path, mark) in self.sources[index].include_paths()? {
+            let id = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone().into());
+            if chain.contains(&id) {
+                let mut files = chain
+                    .iter()
+                    .map(|id| id.display().to_string())
+                    .collect::<Vec<_>>();
+                files.push(id.display().to_string());
+                return Err(MetaError::at(
+                    mark,
+                    format!("include cycle: {}", files.join(" -> ")),
+                ));
+            }
+
+            let child = match self.sources.iter().position(|source| source.id == id) {
+                Some(child) => child,
+                None => {
+                    let content = std::fs::read_to_string(&path).map_err(|err| {
+                        MetaError::at(mark, format!("failed to include '{}': {}", path, err))
+                    })?;
+                    self.add_source(path, &content, chain)?
+                }
+            };
+            self.sources[index].includes.push(child);
+        }
+        chain.pop();
+
+        Ok(index)
+    }
+
+    pub fn resolve(&self) -> Result<Yaml<'_>, MetaError> {
+        let roots = self
+            .sources
+            .iter()
+            .map(Source::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut root = self.combine(0, &roots)?;
+        if let Yaml::Mapping(entries) = &mut root {
+            let mut visits = vec![Visit::Todo; entries.len()];
+            for i in 0..entries.len() {
+                extend(entries, i, &mut visits, &mut vec![])?;
+            }
+        }
+        Ok(root)
+    }
+
+    fn combine<'a>(&self, index: usize, roots: &[Yaml<'a>]) -> Result<Yaml<'a>, MetaError> {
+        let mut own = match roots[index].clone() {
+            Yaml::Mapping(own) => own,
+            // only mappings can include
+            root => return Ok(root),
+        };
+        extract_field(&mut own, "include");
+
+        let mut entries: Vec<Entry> = vec![];
+        for &child in &self.sources[index].includes {
+            match self.combine(child, roots)? {
+                Yaml::Mapping(included) => included
+                    .into_iter()
+                    .for_each(|entry| override_entry(&mut entries, entry)),
+                root => return Err(MetaError::expected("mapping in included file", &root)),
+            }
+        }
+        own.into_iter()
+            .for_each(|entry| override_entry(&mut entries, entry));
+
+        Ok(Yaml::Mapping(entries))
+    }
+
+    pub fn deserialize_into<S, T: Deserialize<S>>(
+        &self,
+        state: &mut S,
+        target: &mut T,
+    ) -> Result<(), MetaError> {
+        self.resolve()
+            .and_then(|node| target.deserialize_into(state, node))
+            .map_err(|err| self.locate(err))
+    }
+
+    /// Resolves error position in whichever file it originates from.
+    pub fn locate(&self, err: MetaError) -> MetaError {
+        self.sources
+            .iter()
+            .fold(err, |err, source| err.locate(&source.file, &source.text))
+    }
+}
+
+impl Source {
+    fn parse(&self) -> Result<Yaml<'_>, MetaError> {
+        let node = parse(&self.text).map_err(|err| parse_error(&self.file, err))?;
+
+        AnchorResolver::new(&self.text, &self.anchors, &self.aliases).resolve(node)
+    }
+
+    fn include_paths(&self) -> Result<Vec<(String, Mark)>, MetaError> {
+        let mut root = match self.parse()? {
+            Yaml::Mapping(root) => root,
+            _ => return Ok(vec![]),
+        };
+
+        let paths = match extract_field(&mut root, "include") {
+            Some(Yaml::Sequence(paths)) => paths,
+            Some(path) => vec![path],
+            None => return Ok(vec![]),
+        };
+
+        let dir = std::path::Path::new(&self.file)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""));
+        paths
+            .into_iter()
+            .map(|path| match path {
+                Yaml::Scalar(str) => Ok((
+                    dir.join(unquote(str)).to_string_lossy().into_owned(),
+                    Mark::of(&path),
+                )),
+                _ => Err(MetaError::expected("path to include", &path)),
+            })
+            .collect()
+    }
+}
+
+/// Parser only exposes the position of an error through its message,
+/// which reads "... at line {line}, column {column} : {message}".
+fn parse_error(file: &str, err: minimal_yaml::YamlParseError) -> MetaError {
+    let text = err.to_string();
+    let position = text.split_once(" at line ").and_then(|(_, rest)| {
+        let (line, rest) = rest.split_once(", column ")?;
+        let column = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+        Some((line.parse().ok()?, column.parse().ok()?))
+    });
+    let (line, column) = position.unwrap_or_default();
+    let message = match text.split_once(" : ") {
+        Some((_, message)) => message.to_string(),
+        None => text.clone(),
+    };
+    MetaError {
+        file: file.to_string(),
+        line,
+        column,
+        message,
+        ..MetaError::default()
+    }
+}
+
+/// Names with their offset in the source.
+type Names = Vec<(usize, String)>;
+
+/// Blanks out anchors (`&name`) and alias markers (`*`) which the parser
+/// rejects. Text keeps its length so positions stay valid. Returns
+/// offsets of anchors and of alias names.
+fn mask_anchors(source: &str) -> (String, Names, Names) {
+    let mut text = source.as_bytes().to_vec();
+    let mut anchors = vec![];
+    let mut aliases = vec![];
+    let mut quote = None;
+    let mut prev = b'\n';
+    let mut gap = false;
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+                prev = c;
+                gap = false;
+            }
+            i += 1;
+            continue;
+        }
+
+        let node_start = prev == b'\n'
+            || matches!(prev, b'[' | b'{' | b',')
+            || (gap && matches!(prev, b':' | b'-' | b'?'));
+        match c {
+            b'\n' => {
+                prev = c;
+                gap = false;
+            }
+            b' ' | b'\t' | b'\r' => gap = true,
+            b'#' if gap || prev == b'\n' => {
+                while i < text.len() && text[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'"' | b'\'' if node_start => quote = Some(c),
+            b'&' | b'*' if node_start => {
+                let end = text[i + 1..]
+                    .iter()
+                    .position(|c| c.is_ascii_whitespace() || b",[]{}".contains(c))
+                    .map_or(text.len(), |len| i + 1 + len);
+                let name = String::from_utf8_lossy(&text[i + 1..end]).into_owned();
+                if c == b'&' {
+                    text[i..end].fill(b' ');
+                    anchors.push((i, name));
+                    gap = true;
+                } else {
+                    text[i] = b' ';
+                    aliases.push((i + 1, name));
+                    prev = text[end - 1];
+                    gap = false;
+                }
+                i = end;
+                continue;
+            }
+            _ => {
+                prev = c;
+                gap = false;
+            }
+        }
+        i += 1;
+    }
+
+    let text = String::from_utf8(text).expect("only whole characters are blanked");
+    (text, anchors, aliases)
+}
+
+struct AnchorResolver<'a, 's> {
+    base: usize,
+    text: &'s str,
+    /// Offset of the first token after each anchor and whether a line break
+    /// separates them, in which case the anchor can also name a block mapping.
+    targets: Vec<(usize, bool)>,
+    anchors: &'s [(usize, String)],
+    bound: Vec<bool>,
+    /// How many collections starting together with a scalar were entered.
+    depth: HashMap<usize, usize>,
+    aliases: &'s [(usize, String)],
+    defined: HashMap<&'s str, Yaml<'a>>,
+}
+
+impl<'a, 's> AnchorResolver<'a, 's> {
+    fn new(text: &'s str, anchors: &'s [(usize, String)], aliases: &'s [(usize, String)]) -> Self {
+        let bytes = text.as_bytes();
+        let targets = anchors
+            .iter()
+            .map(|(at, name)| {
+                let mut i = at + 1 + name.len();
+                let mut block = false;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'\n' => block = true,
+                        b' ' | b'\t' | b'\r' => {}
+                        b'#' => {
+                            while i < bytes.len() && bytes[i] != b'\n' {
+                                i += 1;
+                            }
+                            continue;
+                        }
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                (i, block)
+            })
+            .collect();
+
+        Self {
+            base: text.as_ptr() as usize,
+            text,
+            targets,
+            anchors,
+            bound: vec![false; anchors.len()],
+            depth: HashMap::new(),
+            aliases,
+            defined: HashMap::new(),
+        }
+    }
+
+    /// Offsets of the `-`, `[` and `{` opening the collections which start
+    /// together with the scalar at `first`, outermost first.
+    fn openings(&self, first: usize) -> Vec<usize> {
+        let text = self.text.as_bytes();
+        let mut openings = vec![];
+        let mut i = first;
+        while i > 0 {
+            i -= 1;
+            match text[i] {
+                b' ' | b'\t' | b'\r' | b'\n' => {}
+                b'[' | b'{' => openings.push(i),
+                b'-' if i == 0 || text[i - 1].is_ascii_whitespace() => openings.push(i),
+                _ => break,
+            }
+        }
+        openings.reverse();
+        openings
+    }
+
+    /// An anchor is blanked out of the text, in front of a key this shifts the
+    /// key and with it the indentation of the mapping, so it is refused.
+    fn check_key(&self, key: &Yaml) -> Result<(), MetaError> {
+        let at = Mark::of(key).0.wrapping_sub(self.base);
+        let anchored =
+            (0..self.anchors.len()).find(|&i| !self.bound[i] && self.targets[i] == (at, false));
+        match anchored {
+            Some(i) if matches!(key, Yaml::Scalar(_)) => Err(MetaError::new(
+                key,
+                format!(
+                    "anchor '{}' on a mapping key is not supported",
+                    self.anchors[i].1
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Anchors are attached to the node starting at the first token after
+    /// them, this means anchoring empty collections is not supported.
+    fn resolve(&mut self, node: Yaml<'a>) -> Result<Yaml<'a>, MetaError> {
+        let mark = Mark::of(&node);
+        let first = mark.0.wrapping_sub(self.base);
+        // the first scalar of a collection is its mark, find where it opens
+        let (start, needs_break) = match &node {
+            _ if mark.is_unknown() => (None, false),
+            Yaml::Scalar(_) => (Some(first), false),
+            Yaml::Sequence(_) | Yaml::Mapping(_) => {
+                let openings = self.openings(first);
+                let depth = self.depth.entry(first).or_default();
+                let opening = openings.get(*depth).copied();
+                match opening {
+                    Some(at)
+                        if matches!(node, Yaml::Sequence(_))
+                            || self.text.as_bytes()[at] == b'{' =>
+                    {
+                        *depth += 1;
+                        (Some(at), false)
+                    }
+                    _ => (Some(first), true),
+                }
+            }
+        };
+        let anchors = (0..self.anchors.len())
+            .filter(|&i| {
+                let (target, after_break) = self.targets[i];
+                !self.bound[i] && start == Some(target) && (after_break || !needs_break)
+            })
+            .collect::<Vec<_>>();
+        for &i in &anchors {
+            self.bound[i] = true;
+        }
+
+        let node = match node {
+            Yaml::Scalar(_) => match self.aliases.iter().find(|&&(at, _)| at == first) {
+                Some((_, name)) => {
+                    self.defined.get(name.as_str()).cloned().ok_or_else(|| {
+                        MetaError::new(&node, format!("unknown anchor '{}'", name))
+                    })?
+                }
+                None => node,
+            },
+            Yaml::Sequence(seq) => Yaml::Sequence(
+                seq.into_iter()
+                    .map(|item| self.resolve(item))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Yaml::Mapping(map) => Yaml::Mapping(merge_keys(
+                map.into_iter()
+                    .map(|Entry { key, value }| {
+                        self.check_key(&key)?;
+                        Ok(Entry::new(self.resolve(key)?, self.resolve(value)?))
+                    })
+                    .collect::<Result<_, MetaError>>()?,
+            )?),
+        };
+
+        for i in anchors {
+            self.defined.insert(&self.anchors[i].1, node.clone());
+        }
+
+        Ok(node)
+    }
+}
+
+/// Expands `<<` entries, explicit entries and earlier merges take precedence.
+fn merge_keys(entries: Vec<Entry>) -> Result<Vec<Entry>, MetaError> {
+    let merge_key = Yaml::Scalar("<<");
+    if !entries.iter().any(|entry| entry.key == merge_key) {
+        return Ok(entries);
+    }
+
+    let explicit = entries
+        .iter()
+        .filter(|entry| entry.key != merge_key)
+        .map(|entry| entry.key.clone())
+        .collect::<Vec<_>>();
+    let mut result: Vec<Entry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.key != merge_key {
+            result.push(entry);
+            continue;
+        }
+
+        let merged = match entry.value {
+            Yaml::Sequence(seq) => seq,
+            value => vec![value],
+        };
+        for value in merged {
+            match value {
+                Yaml::Mapping(map) => {
+                    for entry in map {
+                        if !explicit.contains(&entry.key)
+                            && !result.iter().any(|other| other.key == entry.key)
+                        {
+                            result.push(entry);
+                        }
+                    }
+                }
+                _ => return Err(MetaError::expected("mapping to merge", &value)),
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn override_entry<'a>(entries: &mut Vec<Entry<'a>>, entry: Entry<'a>) {
+    match entries.iter_mut().find(|other| other.key == entry.key) {
+        Some(other) => *other = entry,
+        None => entries.push(entry),
+    }
+}
+
+fn deep_merge<'a>(base: Yaml<'a>, over: Yaml<'a>) -> Yaml<'a> {
+    match (base, over) {
+        (Yaml::Mapping(mut base), Yaml::Mapping(over)) => {
+            for entry in over {
+                match base.iter_mut().find(|other| other.key == entry.key) {
+                    Some(other) => {
+                        let value = std::mem::replace(&mut other.value, Yaml::Sequence(vec![]));
+                        other.value = deep_merge(value, entry.value);
+                    }
+                    None => base.push(entry),
+                }
+            }
+            Yaml::Mapping(base)
+        }
+        (_, over) => over,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    Todo,
+    InProgress,
+    Done,
+}
+
+fn extend(
+    entries: &mut [Entry],
+    index: usize,
+    visits: &mut [Visit],
+    chain: &mut Vec<usize>,
+) -> Result<(), MetaError> {
+    match visits[index] {
+        Visit::Done => return Ok(()),
+        Visit::InProgress => {
+            let names = chain
+                .iter()
+                .chain(Some(&index))
+                .map(|&i| entries[i].key.to_string())
+                .collect::<Vec<_>>();
+            return Err(MetaError::new(
+                &entries[index].key,
+                format!("extends cycle: {}", names.join(" -> ")),
+            ));
+        }
+        Visit::Todo => visits[index] = Visit::InProgress,
+    }
+
+    let parents = match &mut entries[index].value {
+        Yaml::Mapping(map) => match extract_field(map, "extends") {
+            Some(Yaml::Sequence(parents)) => parents,
+            Some(parent) => vec![parent],
+            None => vec![],
+        },
+        _ => vec![],
+    };
+
+    chain.push(index);
+    let mut base = Yaml::Mapping(vec![]);
+    for parent in parents {
+        let parent_index = match parent {
+            Yaml::Scalar(name) => entries
+                .iter()
+                .position(
+                    |entry| matches!(entry.key, Yaml::Scalar(key) if unquote(key) == unquote(name)),
+                )
+                .ok_or_else(|| {
+                    MetaError::new(&parent, format!("cannot extend unknown '{}'", name))
+                })?,
+            _ => return Err(MetaError::expected("name to extend", &parent)),
+        };
+        extend(entries, parent_index, visits, chain)?;
+        base = deep_merge(base, entries[parent_index].value.clone());
+    }
+    chain.pop();
+
+    if base != Yaml::Mapping(vec![]) {
+        let own = std::mem::replace(&mut entries[index].value, Yaml::Sequence(vec![]));
+        entries[index].value = deep_merge(base, own);
+    }
+    visits[index] = Visit::Done;
+
+    Ok(())
+}
+
+/// Position of a node inside its source text. Scalars of parsed [`Yaml`]
+/// borrow from the source so their address is enough to find them later.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Mark(usize);
+
+impl Mark {
+    /// Collections are marked by their first scalar, empty ones are unknown.
+    pub fn of(node: &Yaml) -> Self {
+        match node {
+            Yaml::Scalar(str) => Self(str.as_ptr() as usize),
+            Yaml::Sequence(seq) => seq.first().map(Self::of).unwrap_or_default(),
+            Yaml::Mapping(map) => map
+                .first()
+                .map(|entry| Self::of(&entry.key))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns line and column, both counted from 1, if mark points into `source`.
+    pub fn locate(&self, source: &str) -> Option<(usize, usize)> {
+        let start = source.as_ptr() as usize;
+        if self.is_unknown() || self.0 < start || self.0 > start + source.len() {
+            return None;
+        }
+
+        let before = &source[..self.0 - start];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line = before.matches('\n').count() + 1;
+        let column = before[line_start..].chars().count() + 1;
+        Some((line, column))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetaError {
+    pub file: String,
+    /// Line of the offending node, 0 if unknown.
+    pub line: usize,
+    pub column: usize,
+    /// Fields and indexes leading to the offending node, outermost first.
+    pub path: Vec<String>,
+    pub message: String,
+    mark: Mark,
+}
+
+impl MetaError {
+    pub fn new(node: &Yaml, message: impl Into<String>) -> Self {
+        Self::at(Mark::of(node), message)
+    }
+
+    pub fn at(mark: Mark, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            mark,
+            ..Self::default()
+        }
+    }
+
+    pub fn expected(what: &str, node: &Yaml) -> Self {
+        Self::new(node, format!("expected {}, got {:?}", what, node))
+    }
+
+    /// Prepends field to the path, called while error propagates outwards.
+    pub fn inside(mut self, field: impl ToString) -> Self {
+        self.path.insert(0, field.to_string());
+        self
+    }
+
+    pub fn inside_index(self, index: usize) -> Self {
+        self.inside(format_args!("[{}]", index))
+    }
+
+    /// Resolves the position if error originates from `source`
+    /// and was not located yet.
+    pub fn locate(mut self, file: &str, source: &str) -> Self {
+        if self.is_located() {
+            return self;
+        }
+
+        if let Some((line, column)) = self.mark.locate(source) {
+            self.file = file.to_string();
+            self.line = line;
+            self.column = column;
+        }
+
+        self
+    }
+
+    pub fn is_located(&self) -> bool {
+        self.line != 0
+    }
+}
+
+impl fmt::Display for MetaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+            if self.is_located() {
+                write!(f, "{}:{}:", self.line, self.column)?;
+            }
+            write!(f, " ")?;
+        }
+
+        if !self.path.is_empty() {
+            write!(f, "inside ")?;
+            for (i, segment) in self.path.iter().enumerate() {
+                if i != 0 && !segment.starts_with('[') {
+                    write!(f, ".")?;
+                }
+                write!(f, "{}", segment)?;
+            }
+            write!(f, ": ")?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MetaError {}
+
+/// Owned counterpart of [`Yaml`] produced by [`Serialize`]. Displays as
+/// block style yaml, entries keep their order. Displaying a scalar the
+/// parser could not read back fails, [`Node::scalar`] checks for that.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Printed as `~`, unlike scalar it is never quoted.
+    Null,
+    Scalar(String),
+    Sequence(Vec<Node>),
+    Mapping(Vec<(String, Node)>),
+}
+
+impl Node {
+    const INDENT: usize = 2;
+
+    pub fn scalar(str: impl Into<String>) -> Result<Self, MetaError> {
+        let str = str.into();
+        check_scalar(&str)?;
+        Ok(Node::Scalar(str))
+    }
+
+    /// Displays the node, but fails where displaying would, on a scalar built
+    /// past [`Node::scalar`] that cannot be written.
+    pub fn to_yaml(&self) -> Result<String, MetaError> {
+        self.check()?;
+        Ok(self.to_string())
+    }
+
+    fn check(&self) -> Result<(), MetaError> {
+        match self {
+            Node::Null => Ok(()),
+            Node::Scalar(str) => check_scalar(str),
+            Node::Sequence(seq) => seq
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, item)| item.check().map_err(|err| err.inside_index(i))),
+            Node::Mapping(map) => map.iter().try_for_each(|(key, value)| {
+                check_scalar(key)?;
+                value.check().map_err(|err| err.inside(key))
+            }),
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        match self {
+            Node::Null => writeln!(f, "{:indent$}~", "", indent = indent),
+            Node::Scalar(str) => {
+                writeln!(
+                    f,
+                    "{:indent$}{}",
+                    "",
+                    quote(str).ok_or(fmt::Error)?,
+                    indent = indent
+                )
+            }
+            Node::Sequence(seq) => {
+                for item in seq {
+                    write!(f, "{:indent$}-", "", indent = indent)?;
+                    item.write_value(f, indent)?;
+                }
+                Ok(())
+            }
+            Node::Mapping(map) => {
+                for (key, value) in map {
+                    let key = quote_key(key).ok_or(fmt::Error)?;
+                    write!(f, "{:indent$}{}:", "", key, indent = indent)?;
+                    value.write_value(f, indent)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes node that follows `key:` or `-` on the same line.
+    fn write_value(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        match self {
+            Node::Null => writeln!(f, " ~"),
+            Node::Scalar(str) => writeln!(f, " {}", quote(str).ok_or(fmt::Error)?),
+            Node::Sequence(seq) if seq.is_empty() => writeln!(f, " []"),
+            Node::Mapping(map) if map.is_empty() => writeln!(f, " {{}}"),
+            // compact `- key: value` form is not understood by the parser
+            _ => {
+                writeln!(f)?;
+                self.write(f, indent + Self::INDENT)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Sequence(seq) if seq.is_empty() => writeln!(f, "[]"),
+            Node::Mapping(map) if map.is_empty() => writeln!(f, "{{}}"),
+            _ => self.write(f, 0),
+        }
+    }
+}
+
+/// Quotes the scalar if parser would not read it back verbatim. Parser
+/// does not support escapes so there is no way to write a string that
+/// needs quotes but contains both quote kinds.
+fn quote(str: &str) -> Option<std::borrow::Cow<'_, str>> {
+    let needs_quotes = str.is_empty()
+        || str.starts_with(char::is_whitespace)
+        || str.ends_with(char::is_whitespace)
+        || str.starts_with(|c| "?:,[]{}#&*!|>'\"%@`".contains(c))
+        || str == "-"
+        || str.starts_with("- ")
+        || str.starts_with("---")
+        || str == "~"
+        || str == "null"
+        || str.ends_with(':')
+        || str.contains(": ")
+        // the parser takes any '#' for the start of a comment
+        || str.contains('#')
+        || str.contains(|c| ",[]{}\n\r".contains(c));
+
+    if !needs_quotes {
+        Some(str.into())
+    } else if !str.contains('"') {
+        Some(format!("\"{}\"", str).into())
+    } else if !str.contains('\'') {
+        Some(format!("'{}'", str).into())
+    } else {
+        None
+    }
+}
+
+/// Keys [`Document`] reads as directives, quoted they stay plain keys.
+const DIRECTIVES: [&str; 3] = ["include", "extends", "<<"];
+
+fn quote_key(key: &str) -> Option<std::borrow::Cow<'_, str>> {
+    match DIRECTIVES.contains(&key) {
+        true => Some(format!("\"{}\"", key).into()),
+        false => quote(key),
+    }
+}
+
+fn check_scalar(str: &str) -> Result<(), MetaError> {
+    match quote(str) {
+        Some(_) => Ok(()),
+        None => Err(MetaError {
+            message: format!("cannot write {:?}, it needs quotes but has both kinds", str),
+            ..MetaError::default()
+        }),
+    }
+}
+
+/// Strips quotes parser leaves on quoted scalars.
+pub fn unquote(str: &str) -> &str {
+    match str.as_bytes() {
+        [b'"', .., b'"'] | [b'\'', .., b'\''] => &str[1..str.len() - 1],
+        _ => str,
+    }
+}
+
 macro_rules! impl_deserialize_scalar {
     ($($t:ty),*) => {
         $(
             impl<T> Deserialize<T> for $t {
-                fn deserialize_into(&mut self, _state: &mut T, node: Yaml) -> Result<(), String> {
+                fn deserialize_into(&mut self, _state: &mut T, node: Yaml) -> Result<(), MetaError> {
                     match node {
-                        Yaml::Scalar(s) => *self = s.parse::<$t>().map_err(|e| e.to_string())?,
-                        _ => return Err(format!("expected scalar, got {:?}", node)),
+                        Yaml::Scalar(s) => {
+                            *self = unquote(s)
+                                .parse::<$t>()
+                                .map_err(|e| MetaError::new(&node, e.to_string()))?
+                        }
+                        _ => return Err(MetaError::expected("scalar", &node)),
                     }
 
                     Ok(())
                 }
             }
+
+            impl<T> Serialize<T> for $t {
+                fn serialize(&self, _state: &T) -> Result<Node, MetaError> {
+                    Node::scalar(self.to_string())
+                }
+            }
         )*
     };
 }
 
 impl<T, E: Deserialize<T>> Deserialize<T> for Vec<E> {
-    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), String> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
         match node {
             Yaml::Sequence(seq) => {
                 self.reserve(seq.len());
                 for (i, item) in seq.into_iter().enumerate() {
-                    self.push(
-                        E::deserialize(state, item)
-                            .map_err(|err| format!("at index {}: {}", i, err))?,
-                    );
+                    self.push(E::deserialize(state, item).map_err(|err| err.inside_index(i))?);
                 }
                 Ok(())
             }
-            _ => Err(format!("expected sequence, got {:?}", node)),
+            _ => Err(MetaError::expected("sequence", &node)),
         }
     }
 }
 
+impl<T, E: Serialize<T>> Serialize<T> for Vec<E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_items(state, self.iter())
+    }
+}
+
+fn serialize_items<'a, T, E: Serialize<T> + 'a>(
+    state: &T,
+    items: impl Iterator<Item = &'a E>,
+) -> Result<Node, MetaError> {
+    items
+        .enumerate()
+        .map(|(i, item)| item.serialize(state).map_err(|err| err.inside_index(i)))
+        .collect::<Result<_, _>>()
+        .map(Node::Sequence)
+}
+
+fn deserialize_entries<T, E: Deserialize<T>>(
+    state: &mut T,
+    node: Yaml,
+    mut insert: impl FnMut(String, E),
+) -> Result<(), MetaError> {
+    match node {
+        Yaml::Mapping(map) => {
+            for Entry { key, value } in map {
+                insert(
+                    match key {
+                        Yaml::Scalar(str) => unquote(str).to_string(),
+                        _ => return Err(MetaError::expected("scalar", &key)),
+                    },
+                    E::deserialize(state, value).map_err(|err| err.inside(&key))?,
+                );
+            }
+            Ok(())
+        }
+        _ => Err(MetaError::expected("mapping", &node)),
+    }
+}
+
+fn serialize_entries<'a, T, E: Serialize<T> + 'a>(
+    state: &T,
+    entries: impl Iterator<Item = (&'a String, &'a E)>,
+) -> Result<Node, MetaError> {
+    // sorted so the output does not change between runs
+    let mut entries = entries.collect::<Vec<_>>();
+    entries.sort_unstable_by_key(|&(key, _)| key);
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            check_scalar(key)?;
+            let value = value.serialize(state).map_err(|err| err.inside(key))?;
+            Ok((key.clone(), value))
+        })
+        .collect::<Result<_, _>>()
+        .map(Node::Mapping)
+}
+
 impl<T, E: Deserialize<T>> Deserialize<T> for HashMap<String, E> {
-    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), String> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        if let Yaml::Mapping(map) = &node {
+            self.reserve(map.len());
+        }
+        deserialize_entries(state, node, |key, value| {
+            self.insert(key, value);
+        })
+    }
+}
+
+impl<T, E: Serialize<T>> Serialize<T> for HashMap<String, E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_entries(state, self.iter())
+    }
+}
+
+impl<T, E: Deserialize<T>> Deserialize<T> for BTreeMap<String, E> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        deserialize_entries(state, node, |key, value| {
+            self.insert(key, value);
+        })
+    }
+}
+
+impl<T, E: Serialize<T>> Serialize<T> for BTreeMap<String, E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_entries(state, self.iter())
+    }
+}
+
+impl<T, E: Deserialize<T> + Hash + Eq> Deserialize<T> for HashSet<E> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        match node {
+            Yaml::Sequence(seq) => {
+                self.reserve(seq.len());
+                for (i, item) in seq.into_iter().enumerate() {
+                    let mark = Mark::of(&item);
+                    let item = E::deserialize(state, item).map_err(|err| err.inside_index(i))?;
+                    if !self.insert(item) {
+                        return Err(MetaError::at(mark, "duplicate item").inside_index(i));
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(MetaError::expected("sequence", &node)),
+        }
+    }
+}
+
+impl<T, E: Serialize<T>> Serialize<T> for HashSet<E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        // sorted so the output does not change between runs
+        let mut items = self
+            .iter()
+            .map(|item| {
+                let node = item.serialize(state)?;
+                Ok((node.to_yaml()?, node))
+            })
+            .collect::<Result<Vec<_>, MetaError>>()?;
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(Node::Sequence(
+            items.into_iter().map(|(_, node)| node).collect(),
+        ))
+    }
+}
+
+impl<T, E: Deserialize<T>, const N: usize> Deserialize<T> for [E; N]
+where
+    [E; N]: Default,
+{
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
         match node {
-            Yaml::Mapping(map) => {
-                self.reserve(map.len());
-                for Entry { key, value } in map {
-                    self.insert(
-                        match key {
-                            Yaml::Scalar(str) => str.to_string(),
-                            _ => return Err(format!("expected scalar, got {:?}", key)),
-                        },
-                        E::deserialize(state, value)
-                            .map_err(|err| format!("inside {}: {}", key, err))?,
-                    );
+            Yaml::Sequence(seq) if seq.len() == N => {
+                for (i, (value, item)) in self.iter_mut().zip(seq).enumerate() {
+                    value
+                        .deserialize_into(state, item)
+                        .map_err(|err| err.inside_index(i))?;
                 }
                 Ok(())
             }
-            _ => Err(format!("expected mapping, got {:?}", node)),
+            _ => Err(MetaError::expected(
+                &format!("sequence of {} items", N),
+                &node,
+            )),
+        }
+    }
+}
+
+impl<T, E: Serialize<T>, const N: usize> Serialize<T> for [E; N] {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_items(state, self.iter())
+    }
+}
+
+macro_rules! impl_meta_tuple {
+    ($($len:literal => ($($i:tt $e:ident)*),)*) => {
+        $(
+            impl<T, $($e: Deserialize<T>),*> Deserialize<T> for ($($e,)*) {
+                fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+                    match node {
+                        Yaml::Sequence(seq) if seq.len() == $len => {
+                            let mut items = seq.into_iter();
+                            $(
+                                self.$i
+                                    .deserialize_into(state, items.next().unwrap())
+                                    .map_err(|err| err.inside_index($i))?;
+                            )*
+                            Ok(())
+                        }
+                        _ => Err(MetaError::expected(concat!("sequence of ", $len, " items"), &node)),
+                    }
+                }
+            }
+
+            impl<T, $($e: Serialize<T>),*> Serialize<T> for ($($e,)*) {
+                fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+                    Ok(Node::Sequence(vec![$(
+                        self.$i.serialize(state).map_err(|err| err.inside_index($i))?
+                    ),*]))
+                }
+            }
+        )*
+    };
+}
+
+impl_meta_tuple!(
+    1 => (0 A),
+    2 => (0 A 1 B),
+    3 => (0 A 1 B 2 C),
+    4 => (0 A 1 B 2 C 3 D),
+    5 => (0 A 1 B 2 C 3 D 4 E),
+    6 => (0 A 1 B 2 C 3 D 4 E 5 F),
+    7 => (0 A 1 B 2 C 3 D 4 E 5 F 6 G),
+    8 => (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H),
+);
+
+/// `~` and `null` are none, anything else is deserialized into the value.
+impl<T, E: Deserialize<T>> Deserialize<T> for Option<E> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        match (self.as_mut(), node) {
+            (_, Yaml::Scalar("~" | "null")) => *self = None,
+            (Some(value), node) => value.deserialize_into(state, node)?,
+            (None, node) => *self = Some(E::deserialize(state, node)?),
+        }
+        Ok(())
+    }
+}
+
+impl<T, E: Serialize<T>> Serialize<T> for Option<E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        match self {
+            Some(value) => value.serialize(state),
+            None => Ok(Node::Null),
+        }
+    }
+}
+
+impl<T, E: Deserialize<T>> Deserialize<T> for Box<E> {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        (**self).deserialize_into(state, node)
+    }
+}
+
+impl<T, E: Serialize<T>> Serialize<T> for Box<E> {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        (**self).serialize(state)
+    }
+}
+
+/// Reads numbers from a sequence or from a mapping with given field names.
+fn deserialize_fields<T, E: Deserialize<T>, const N: usize>(
+    state: &mut T,
+    node: Yaml,
+    names: [&str; N],
+) -> Result<[E; N], MetaError> {
+    let mut values = std::array::from_fn(|_| E::default());
+    let mark = Mark::of(&node);
+    match node {
+        Yaml::Sequence(seq) if seq.len() == N => {
+            for (i, (value, item)) in values.iter_mut().zip(seq).enumerate() {
+                value
+                    .deserialize_into(state, item)
+                    .map_err(|err| err.inside_index(i))?;
+            }
+        }
+        Yaml::Mapping(mut map) => {
+            for (value, name) in values.iter_mut().zip(names) {
+                let field = extract_field(&mut map, name).ok_or_else(|| {
+                    MetaError::at(mark, format!("missing required field: {}", name))
+                })?;
+                value
+                    .deserialize_into(state, field)
+                    .map_err(|err| err.inside(name))?;
+            }
         }
+        _ => {
+            return Err(MetaError::expected(
+                &format!("sequence of {} items or mapping of {}", N, names.join(", ")),
+                &node,
+            ))
+        }
+    }
+    Ok(values)
+}
+
+fn serialize_fields<T, E: Serialize<T>, const N: usize>(
+    state: &T,
+    values: [E; N],
+    names: [&str; N],
+) -> Result<Node, MetaError> {
+    names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| Ok((name.to_string(), value.serialize(state)?)))
+        .collect::<Result<_, _>>()
+        .map(Node::Mapping)
+}
+
+/// `[x, y]` or `{x: , y: }`
+impl<T> Deserialize<T> for Vector2 {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        let [x, y] = deserialize_fields(state, node, ["x", "y"])?;
+        *self = Vector2 { x, y };
+        Ok(())
+    }
+}
+
+impl<T> Serialize<T> for Vector2 {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_fields(state, [self.x, self.y], ["x", "y"])
+    }
+}
+
+/// `[x, y, width, height]` or `{x: , y: , width: , height: }`
+impl<T> Deserialize<T> for Rectangle {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        let [x, y, width, height] = deserialize_fields(state, node, ["x", "y", "width", "height"])?;
+        *self = Rectangle {
+            x,
+            y,
+            width,
+            height,
+        };
+        Ok(())
+    }
+}
+
+impl<T> Serialize<T> for Rectangle {
+    fn serialize(&self, state: &T) -> Result<Node, MetaError> {
+        serialize_fields(
+            state,
+            [self.x, self.y, self.width, self.height],
+            ["x", "y", "width", "height"],
+        )
+    }
+}
+
+/// `"#rrggbb"`, `"#rrggbbaa"` (quoted, otherwise it is a comment),
+/// `[r, g, b]`, `[r, g, b, a]` or `{r: , g: , b: , a: }` where `a`
+/// is optional. Missing alpha is opaque.
+impl<T> Deserialize<T> for Color {
+    fn deserialize_into(&mut self, state: &mut T, node: Yaml) -> Result<(), MetaError> {
+        *self = match node {
+            Yaml::Scalar(str) => {
+                let hex = unquote(str)
+                    .strip_prefix('#')
+                    .filter(|hex| hex.len() == 6 || hex.len() == 8)
+                    .filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))
+                    .ok_or_else(|| MetaError::expected("color as #rrggbb or #rrggbbaa", &node))?;
+                let value = u32::from_str_radix(hex, 16).expect("checked hex digits");
+                let value = if hex.len() == 6 {
+                    value << 8 | 0xff
+                } else {
+                    value
+                };
+                let [r, g, b, a] = value.to_be_bytes();
+                Color { r, g, b, a }
+            }
+            Yaml::Sequence(ref seq) if seq.len() == 3 => {
+                let [r, g, b] = deserialize_fields(state, node, ["r", "g", "b"])?;
+                Color { r, g, b, a: 255 }
+            }
+            Yaml::Mapping(ref map) if !map.iter().any(|entry| entry.key == Yaml::Scalar("a")) => {
+                let [r, g, b] = deserialize_fields(state, node, ["r", "g", "b"])?;
+                Color { r, g, b, a: 255 }
+            }
+            _ => {
+                let [r, g, b, a] = deserialize_fields(state, node, ["r", "g", "b", "a"])?;
+                Color { r, g, b, a }
+            }
+        };
+        Ok(())
+    }
+}
+
+impl<T> Serialize<T> for Color {
+    fn serialize(&self, _state: &T) -> Result<Node, MetaError> {
+        let Color { r, g, b, a } = *self;
+        Ok(Node::Scalar(if a == 255 {
+            format!("#{:02x}{:02x}{:02x}", r, g, b)
+        } else {
+            format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+        }))
     }
 }
 
 impl_deserialize_scalar!(
     i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64, bool, String, char
 );
+
+#[cfg(test)]
+mod test {
+    use bitwise::*;
+
+    use super::*;
+
+    #[test]
+    fn error_location() {
+        let source = "first:\n  - 1\n  - 2\nsecond:\n  - 3\n  - nope\n";
+        let mut target = HashMap::<String, Vec<i32>>::new();
+        let err = load_into("stats.yaml", source, &mut (), &mut target).unwrap_err();
+
+        assert_eq!((err.line, err.column), (6, 5));
+        assert_eq!(err.path, vec!["second".to_string(), "[1]".to_string()]);
+        assert_eq!(
+            err.to_string(),
+            "stats.yaml:6:5: inside second[1]: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let source = "first: 1\nsecond: [1, 2\nthird: 3\n";
+        let mut target = HashMap::<String, i32>::new();
+        let err = load_into("stats.yaml", source, &mut (), &mut target).unwrap_err();
+
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(
+            err.to_string(),
+            "stats.yaml:3:1: failed to parse flow sequence"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut value = HashMap::<String, Vec<HashMap<String, String>>>::new();
+        value.insert(
+            "plain".to_string(),
+            vec![
+                HashMap::from([
+                    ("name".to_string(), "hello world".to_string()),
+                    ("tricky".to_string(), "key: value # comment".to_string()),
+                ]),
+                HashMap::new(),
+                HashMap::from([("empty".to_string(), "".to_string())]),
+            ],
+        );
+        value.insert("empty".to_string(), vec![]);
+        value.insert("- dash".to_string(), vec![HashMap::new()]);
+
+        let text = dump(&(), &value).unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "\"- dash\":\n",
+                "  - {}\n",
+                "empty: []\n",
+                "plain:\n",
+                "  -\n",
+                "    name: hello world\n",
+                "    tricky: \"key: value # comment\"\n",
+                "  - {}\n",
+                "  -\n",
+                "    empty: \"\"\n",
+            )
+        );
+
+        let mut loaded = HashMap::new();
+        load_into("dump", &text, &mut (), &mut loaded).unwrap();
+        assert_eq!(loaded, value);
+        assert_eq!(dump(&(), &loaded).unwrap(), text);
+
+        // there are no escapes to write both quote kinds with
+        let both = HashMap::from([("key".to_string(), "x'y\"z: w".to_string())]);
+        assert!(dump(&(), &both).is_err());
+        let key = HashMap::from([("x'y\"z: w".to_string(), "value".to_string())]);
+        assert!(dump(&(), &key).is_err());
+        let plain = HashMap::from([("key".to_string(), "x'y\"z".to_string())]);
+        assert_eq!(dump(&(), &plain).unwrap(), "key: x'y\"z\n");
+        // built past `Node::scalar`, so only writing notices
+        #[derive(PartialEq, Eq, Hash)]
+        struct Raw;
+        impl Serialize<()> for Raw {
+            fn serialize(&self, _: &()) -> Result<Node, MetaError> {
+                Ok(Node::Scalar("x'y\"z: w".to_string()))
+            }
+        }
+        assert!(dump(&(), &Raw).is_err());
+        assert!(dump(&(), &HashSet::from([Raw])).is_err());
+
+        // the parser takes any '#' for a comment, quoted or not after a space
+        let hashes = BTreeMap::from([
+            ("a#b".to_string(), "c#".to_string()),
+            ("#d".to_string(), "e # f".to_string()),
+            ("g".to_string(), "h#i".to_string()),
+        ]);
+        let mut loaded = BTreeMap::new();
+        load_into("dump", &dump(&(), &hashes).unwrap(), &mut (), &mut loaded).unwrap();
+        assert_eq!(loaded, hashes);
+
+        // keys named like directives load back as plain keys
+        for key in DIRECTIVES {
+            let flat = BTreeMap::from([(key.to_string(), "1".to_string())]);
+            let nested = BTreeMap::from([("a".to_string(), flat.clone())]);
+            let mut loaded = BTreeMap::new();
+            load_into("dump", &dump(&(), &flat).unwrap(), &mut (), &mut loaded).unwrap();
+            assert_eq!(loaded, flat);
+            let mut loaded = BTreeMap::new();
+            load_into("dump", &dump(&(), &nested).unwrap(), &mut (), &mut loaded).unwrap();
+            assert_eq!(loaded, nested);
+        }
+    }
+
+    #[test]
+    fn std_and_raylib_types() {
+        type Types = (
+            Option<i32>,
+            Option<String>,
+            [u8; 3],
+            (i32, String),
+            BTreeMap<String, Box<f32>>,
+            HashSet<u8>,
+            (Vector2, Rectangle, Vec<Color>),
+        );
+        let source = concat!(
+            "- ~\n",
+            "- 'null'\n",
+            "- [1, 2, 3]\n",
+            "- [4, four]\n",
+            "- {b: 2, a: 1.5}\n",
+            "- [3, 1, 2]\n",
+            "-\n",
+            "  - [1, 2]\n",
+            "  - {x: 1, y: 2, width: 3, height: 4}\n",
+            "  - [\"#ff8000\", \"#ff800080\", [1, 2, 3], {r: 1, g: 2, b: 3, a: 4}]\n",
+        );
+        let mut value = Types::default();
+        load_into("types.yaml", source, &mut (), &mut value).unwrap();
+
+        assert_eq!(value.0, None);
+        assert_eq!(value.1.as_deref(), Some("null"));
+        assert_eq!(value.2, [1, 2, 3]);
+        assert_eq!(value.3, (4, "four".to_string()));
+        assert_eq!(*value.4["a"], 1.5);
+        assert_eq!(value.5, HashSet::from([1, 2, 3]));
+        assert_eq!(value.6 .0, Vector2 { x: 1.0, y: 2.0 });
+        assert_eq!(value.6 .1.height, 4.0);
+        assert_eq!(
+            value.6 .2,
+            vec![
+                Color::new(255, 128, 0, 255),
+                Color::new(255, 128, 0, 128),
+                Color::new(1, 2, 3, 255),
+                Color::new(1, 2, 3, 4),
+            ]
+        );
+
+        let text = dump(&(), &value).unwrap();
+        let mut loaded = Types::default();
+        load_into("types.yaml", &text, &mut (), &mut loaded).unwrap();
+        assert_eq!(loaded, value);
+
+        for color in ["'#+fffff'", "ff8000", "'#ff800'", "'#ff80000'", "'#ff800g'"] {
+            let err = load_into("color.yaml", color, &mut (), &mut Color::new(0, 0, 0, 0));
+            assert!(err.is_err(), "{} parsed as a color", color);
+        }
+
+        let err = load_into("types.yaml", "[1, 1]", &mut (), &mut HashSet::<u8>::new());
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "types.yaml:1:5: inside [1]: duplicate item"
+        );
+    }
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    struct Texture(usize);
+
+    crate::impl_deserialize_state!(Texture);
+
+    struct Textures(Vec<&'static str>);
+
+    impl Intern<Texture> for Textures {
+        fn resolve(&mut self, name: &str) -> Option<Texture> {
+            self.0.iter().position(|&other| other == name).map(Texture)
+        }
+
+        fn name_of(&self, id: &Texture) -> Option<&str> {
+            self.0.get(id.0).copied()
+        }
+
+        fn names(&self) -> Vec<&str> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn interned() {
+        let mut state = Textures(vec!["tower_base", "wall", "floor"]);
+        let mut textures = Vec::<Texture>::new();
+        load_into("t.yaml", "[wall, floor]", &mut state, &mut textures).unwrap();
+        assert_eq!(textures, vec![Texture(1), Texture(2)]);
+        assert_eq!(dump(&state, &textures).unwrap(), "- wall\n- floor\n");
+
+        let err = load_into("t.yaml", "[tower_bse]", &mut state, &mut textures).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "t.yaml:1:2: inside [0]: Texture 'tower_bse' not found, did you mean 'tower_base'?"
+        );
+
+        let err = load_into("t.yaml", "[roof]", &mut state, &mut textures).unwrap_err();
+        assert_eq!(err.message, "Texture 'roof' not found");
+    }
+
+    store::create_access!(Sprite);
+
+    crate::impl_deserialize_state!(Sprite);
+
+    #[test]
+    fn interned_table() {
+        let options = store::MapOptions {
+            keep_keys: true,
+            ..Default::default()
+        };
+        let mut table = Table::<Sprite, u32>::with_options(0, options);
+        let tower = table.insert("tower", 1);
+        let wall = table.insert("wall", 2);
+
+        let mut sprites = Vec::<Sprite>::new();
+        load_into("s.yaml", "[wall, tower]", &mut table, &mut sprites).unwrap();
+        assert_eq!(sprites, vec![wall, tower]);
+        let text = dump(&table, &sprites).unwrap();
+        assert_eq!(text, "- wall\n- tower\n");
+        let mut loaded = Vec::<Sprite>::new();
+        load_into("s.yaml", &text, &mut table, &mut loaded).unwrap();
+        assert_eq!(loaded, sprites);
+
+        let err = load_into("s.yaml", "[towr]", &mut table, &mut sprites).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Sprite 'towr' not found, did you mean 'tower'?"
+        );
+
+        table.remove("wall");
+        let err = dump(&table, &vec![wall]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inside [0]: Sprite Sprite(1, 0) has no name"
+        );
+    }
+
+    type Stats = HashMap<String, HashMap<String, HashMap<String, i32>>>;
+
+    #[test]
+    fn anchors_and_merge_keys() {
+        let source = concat!(
+            "small: &small\n",
+            "  health: &health\n",
+            "    max: 10\n",
+            "    defense: 1\n",
+            "big:\n",
+            "  health:\n",
+            "    <<: *health\n",
+            "    max: 100\n",
+            "  shield: *health\n",
+            "copy: *small\n",
+        );
+        let mut stats = Stats::new();
+        load_into("stats.yaml", source, &mut (), &mut stats).unwrap();
+
+        assert_eq!(stats["big"]["health"]["max"], 100);
+        assert_eq!(stats["big"]["health"]["defense"], 1);
+        assert_eq!(stats["big"]["shield"]["max"], 10);
+        assert_eq!(stats["copy"], stats["small"]);
+
+        let err = load_into("stats.yaml", "a: *nope\n", &mut (), &mut stats).unwrap_err();
+        assert_eq!(err.to_string(), "stats.yaml:1:5: unknown anchor 'nope'");
+    }
+
+    #[test]
+    fn anchored_items() {
+        let check = |source: &str, expanded: &str| {
+            let document = Document::from_source("stats.yaml", source).unwrap();
+            let resolved = document.sources[0].parse().unwrap();
+            assert_eq!(resolved, parse(expanded).unwrap(), "{:?}", source);
+        };
+        check(
+            "a: [1, 2]\nb:\n  - &x 1\nc: *x\n",
+            "a: [1, 2]\nb:\n  - 1\nc: 1\n",
+        );
+        check(
+            "list:\n  - &h\n    max: 1\n  - *h\n",
+            "list: [{max: 1}, {max: 1}]\n",
+        );
+        check("[&a 1, *a]", "[1, 1]");
+        check(
+            "a: &outer\n  - &inner\n    - 1\nb: *inner\nc: *outer\n",
+            "a: [[1]]\nb: [1]\nc: [[1]]\n",
+        );
+
+        // blanking these would shift the mapping and lose entries
+        for source in ["&k key: 1\nb: *k\n", "list:\n  - &k key: 1\n    b: 2\n"] {
+            let err = Document::from_source("stats.yaml", source)
+                .and_then(|document| document.resolve().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.message, "anchor 'k' on a mapping key is not supported");
+            assert_ne!(err.line, 0, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn extends() {
+        let source = concat!(
+            "base:\n",
+            "  health:\n",
+            "    max: 10\n",
+            "    defense: 1\n",
+            "tower:\n",
+            "  extends: base\n",
+            "  health:\n",
+            "    max: 50\n",
+            "wall:\n",
+            "  extends: [tower]\n",
+            "  damage:\n",
+            "    value: 3\n",
+        );
+        let mut stats = Stats::new();
+        load_into("stats.yaml", source, &mut (), &mut stats).unwrap();
+
+        assert_eq!(stats["tower"]["health"]["max"], 50);
+        assert_eq!(stats["tower"]["health"]["defense"], 1);
+        assert_eq!(stats["wall"]["health"]["max"], 50);
+        assert_eq!(stats["wall"]["damage"]["value"], 3);
+
+        let source = "a:\n  extends: b\nb:\n  extends: a\n";
+        let err = load_into("stats.yaml", source, &mut (), &mut stats).unwrap_err();
+        assert_eq!(err.message, "extends cycle: a -> b -> a");
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("meta_data_includes_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("common")).unwrap();
+        let write = |name: &str, content: &str| std::fs::write(dir.join(name), content).unwrap();
+        write(
+            "common/base.yaml",
+            "base:\n  health:\n    max: 10\nshared:\n  a:\n    b: 1\n",
+        );
+        write(
+            "main.yaml",
+            "include: common/base.yaml\ntower:\n  extends: base\nshared:\n  a:\n    b: 2\n",
+        );
+        write("cycle_a.yaml", "include: [cycle_b.yaml]\n");
+        write("cycle_b.yaml", "include: cycle_a.yaml\n");
+
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut stats = Stats::new();
+        Document::load(&path("main.yaml"))
+            .unwrap()
+            .deserialize_into(&mut (), &mut stats)
+            .unwrap();
+        assert_eq!(stats["tower"]["health"]["max"], 10);
+        assert_eq!(stats["shared"]["a"]["b"], 2);
+
+        let err = Document::load(&path("cycle_a.yaml")).err().unwrap();
+        assert!(err.message.starts_with("include cycle:"), "{}", err);
+        assert_eq!(err.file, path("cycle_b.yaml"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
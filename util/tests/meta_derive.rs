@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use util::{meta_data, prelude::Meta};
+
+#[derive(Default)]
+struct Loader;
+
+#[derive(Meta, Debug, Default, PartialEq)]
+#[meta_parser(Loader)]
+struct Tower {
+    #[meta_required]
+    name: String,
+    health: i32,
+    tags: Vec<String>,
+    notes: HashMap<String, String>,
+}
+
+#[test]
+fn derive_round_trip() {
+    let tower = Tower {
+        name: "archer: tier 2".to_string(),
+        health: 50,
+        tags: vec!["it's".to_string(), "\"quoted\"".to_string()],
+        notes: HashMap::from([("# not a comment".to_string(), "[]".to_string())]),
+    };
+
+    let text = meta_data::dump(&Loader, &tower).unwrap();
+    let mut loaded = Tower::default();
+    meta_data::load_into("tower.yaml", &text, &mut Loader, &mut loaded).unwrap();
+    assert_eq!(loaded, tower);
+}
+
+#[test]
+fn unrepresentable_field() {
+    let tower = Tower {
+        tags: vec!["fine".to_string(), "x'y\"z: w".to_string()],
+        ..Default::default()
+    };
+
+    let err = meta_data::dump(&Loader, &tower).unwrap_err();
+    assert_eq!(err.path, ["tags", "[1]"]);
+    assert_eq!(
+        err.to_string(),
+        "inside tags[1]: cannot write \"x'y\\\"z: w\", it needs quotes but has both kinds"
+    );
+}